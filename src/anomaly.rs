@@ -0,0 +1,115 @@
+//! Opt-in checks for NaN and infinite values in the forward and backward passes.
+
+use crate::error::{Error, Result};
+use crate::float::Float;
+use crate::op::Op;
+use std::cell::Cell;
+
+thread_local! {
+    static ANOMALY_DETECTION: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether operations on this thread currently check their results.
+pub fn is_anomaly_detection_enabled() -> bool {
+    ANOMALY_DETECTION.with(Cell::get)
+}
+
+/// Makes operations on this thread check their results until the guard is dropped.
+///
+/// Like `NoGradGuard` the setting is per thread: other threads don't see it, apart from the
+/// ones started by `MultiLayerPerceptron::par_forward` and `par_back_propagate`, which take
+/// the setting of their caller.
+///
+/// While enabled, every operation on `Value`s and every `Program::forward` fails as soon as
+/// an output is NaN or infinite, and `Value::back_propagate` and `Program::back_propagate`
+/// fail as soon as the gradient flowing into an input is. The `try_` variants return an
+/// `Error::Anomaly`, the others panic with its message, which names the operation, its inputs
+/// and, for gradients, the path from the root to the operation. Dropping the guard restores
+/// the previous state, so guards can be nested.
+pub struct AnomalyDetectionGuard {
+    previous: bool,
+}
+
+impl AnomalyDetectionGuard {
+    pub fn new() -> AnomalyDetectionGuard {
+        AnomalyDetectionGuard {
+            previous: ANOMALY_DETECTION.with(|enabled| enabled.replace(true)),
+        }
+    }
+}
+
+impl Default for AnomalyDetectionGuard {
+    fn default() -> Self {
+        AnomalyDetectionGuard::new()
+    }
+}
+
+impl Drop for AnomalyDetectionGuard {
+    fn drop(&mut self) {
+        ANOMALY_DETECTION.with(|enabled| enabled.set(self.previous));
+    }
+}
+
+/// Runs `f` with anomaly detection enabled, see `AnomalyDetectionGuard`.
+pub fn detect_anomaly<R, F: FnOnce() -> R>(f: F) -> R {
+    let _guard = AnomalyDetectionGuard::new();
+    f()
+}
+
+// Fails if `output`, computed by `op` from the inputs described by `inputs`, isn't finite.
+pub(crate) fn check_forward<T: Float>(
+    op: &Op<T>,
+    output: T,
+    inputs: impl FnOnce() -> String,
+) -> Result<()> {
+    if output.is_finite() {
+        return Ok(());
+    }
+
+    Err(Error::Anomaly {
+        op: op.name().to_string(),
+        problem: format!("{} output in the forward pass", describe(output)),
+        inputs: inputs(),
+        grad: None,
+        path: vec![],
+    })
+}
+
+// Fails if the gradient flowing from the output of `op` into one of its inputs isn't finite.
+// `grad` is the gradient of the output and `path` lists the nodes from the root to the output.
+pub(crate) fn check_backward<T: Float>(
+    op: &Op<T>,
+    grad: T,
+    local_grads: &[T],
+    inputs: impl FnOnce() -> String,
+    path: impl FnOnce() -> Vec<String>,
+) -> Result<()> {
+    let Some((index, child_grad)) = local_grads
+        .iter()
+        .map(|&local_grad| local_grad * grad)
+        .enumerate()
+        .find(|(_, child_grad)| !child_grad.is_finite())
+    else {
+        return Ok(());
+    };
+
+    Err(Error::Anomaly {
+        op: op.name().to_string(),
+        problem: format!(
+            "{} gradient for its input {} in the backward pass",
+            describe(child_grad),
+            index
+        ),
+        inputs: inputs(),
+        grad: Some(grad.to_string()),
+        path: path(),
+    })
+}
+
+fn describe<T: Float>(value: T) -> &'static str {
+    if value.is_nan() {
+        "a NaN"
+    } else {
+        "an infinite"
+    }
+}
@@ -0,0 +1,41 @@
+//! Dense matrices of derivatives for sensitivity analysis of small models.
+
+use crate::float::Float;
+use crate::value::{RefValue, Value};
+
+/// Computes the derivative of each of `outputs` with respect to each of `inputs`.
+///
+/// Row `i` holds the gradient of `outputs[i]`, with one column per input in order, so an input
+/// an output doesn't depend on gets 0. Each row is a separate `Value::back_propagate`, which
+/// sets the `grad` of the graph of that output as a side effect.
+pub fn jacobian<T: Float>(outputs: &[RefValue<T>], inputs: &[RefValue<T>]) -> Vec<Vec<T>> {
+    outputs
+        .iter()
+        .map(|output| {
+            // Inputs the output doesn't depend on aren't reset by the back propagation.
+            for input in inputs {
+                input.get().borrow_mut().grad = T::zero();
+            }
+            Value::back_propagate(output);
+
+            inputs.iter().map(|input| input.get().borrow().grad).collect()
+        })
+        .collect()
+}
+
+/// Computes the second derivatives of the graph built by `f` at `inputs`.
+///
+/// `f` is called once with leaves holding `inputs`. Its gradient is built as a graph with
+/// `Value::gradients`, and the Hessian is the Jacobian of that gradient, so entry `[i][j]` is
+/// the derivative with respect to input `j` of the derivative with respect to input `i`.
+pub fn hessian<T, F>(f: F, inputs: &[T]) -> Vec<Vec<T>>
+where
+    T: Float,
+    F: Fn(&[RefValue<T>]) -> RefValue<T>,
+{
+    let leaves: Vec<RefValue<T>> = inputs.iter().map(|&x| Value::leaf(x)).collect();
+    let output = f(&leaves);
+    let grads = Value::gradients(&output, &leaves);
+
+    jacobian(&grads, &leaves)
+}
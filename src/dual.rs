@@ -0,0 +1,180 @@
+use crate::float::Float;
+use crate::op::Op;
+use std::fmt;
+use std::ops;
+
+/// A dual number for forward mode automatic differentiation.
+///
+/// Carries a value together with its derivative along one direction. Every operation
+/// updates both at once, so a single forward pass gives a directional derivative without
+/// building a graph, which suits functions with few inputs and many outputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dual<T: Float = f32> {
+    pub value: T,
+    pub deriv: T,
+}
+
+impl<T: Float> Dual<T> {
+    pub fn new(value: T, deriv: T) -> Dual<T> {
+        Dual { value, deriv }
+    }
+
+    /// A value that doesn't depend on the input, its derivative is 0.
+    pub fn constant(value: T) -> Dual<T> {
+        Dual::new(value, T::zero())
+    }
+
+    /// The input being differentiated against, its derivative is 1.
+    pub fn variable(value: T) -> Dual<T> {
+        Dual::new(value, T::one())
+    }
+
+    /// Applies `op` to `args`, propagating the derivative with the chain rule.
+    pub fn apply(op: &Op<T>, args: &[Dual<T>]) -> Dual<T> {
+        let inputs: Vec<T> = args.iter().map(|arg| arg.value).collect();
+        let value = op.forward(&inputs);
+        let deriv = op
+            .local_grads(&inputs, value)
+            .into_iter()
+            .zip(args)
+            .fold(T::zero(), |acc, (local_grad, arg)| acc + local_grad * arg.deriv);
+
+        Dual { value, deriv }
+    }
+
+    pub fn relu(self) -> Dual<T> {
+        Dual::apply(&Op::Relu, &[self])
+    }
+
+    pub fn tanh(self) -> Dual<T> {
+        Dual::apply(&Op::Tanh, &[self])
+    }
+
+    pub fn exp(self) -> Dual<T> {
+        Dual::apply(&Op::Exp, &[self])
+    }
+
+    pub fn pow(self, other: T) -> Dual<T> {
+        Dual::apply(&Op::Pow(other), &[self])
+    }
+}
+
+/// Computes the Jacobian-vector product of `f` at `primals` along `tangents`.
+///
+/// Returns the outputs of `f` and the derivative of each output in the direction of `tangents`,
+/// using a single forward pass.
+pub fn jvp<T, F>(f: F, primals: &[T], tangents: &[T]) -> (Vec<T>, Vec<T>)
+where
+    T: Float,
+    F: Fn(&[Dual<T>]) -> Vec<Dual<T>>,
+{
+    assert_eq!(
+        primals.len(),
+        tangents.len(),
+        "primals and tangents must have the same length"
+    );
+
+    let inputs: Vec<Dual<T>> = primals
+        .iter()
+        .zip(tangents)
+        .map(|(&value, &deriv)| Dual::new(value, deriv))
+        .collect();
+
+    f(&inputs)
+        .into_iter()
+        .map(|output| (output.value, output.deriv))
+        .unzip()
+}
+
+impl<T: Float> From<T> for Dual<T> {
+    fn from(value: T) -> Self {
+        Dual::constant(value)
+    }
+}
+
+impl<T: Float> From<i32> for Dual<T> {
+    fn from(value: i32) -> Self {
+        Dual::constant(T::from_f64(value as f64))
+    }
+}
+
+impl<T: Float> fmt::Display for Dual<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Dual(value={:.4}, deriv={:.4})", self.value, self.deriv)
+    }
+}
+
+fn add<T: Float>(lhs: Dual<T>, rhs: Dual<T>) -> Dual<T> {
+    Dual::apply(&Op::Add, &[lhs, rhs])
+}
+
+fn mul<T: Float>(lhs: Dual<T>, rhs: Dual<T>) -> Dual<T> {
+    Dual::apply(&Op::Mul, &[lhs, rhs])
+}
+
+fn sub<T: Float>(lhs: Dual<T>, rhs: Dual<T>) -> Dual<T> {
+    Dual::apply(&Op::Sub, &[lhs, rhs])
+}
+
+fn div<T: Float>(lhs: Dual<T>, rhs: Dual<T>) -> Dual<T> {
+    Dual::apply(&Op::Div, &[lhs, rhs])
+}
+
+impl<T: Float> ops::Neg for Dual<T> {
+    type Output = Dual<T>;
+
+    fn neg(self) -> Self::Output {
+        Dual::apply(&Op::Neg, &[self])
+    }
+}
+
+// Implements a binary operator between duals, with anything convertible into a dual on the
+// right hand side and integers on the left hand side.
+macro_rules! impl_dual_op {
+    ($trait:ident, $method:ident) => {
+        impl<T: Float, R: Into<Dual<T>>> ops::$trait<R> for Dual<T> {
+            type Output = Dual<T>;
+
+            fn $method(self, rhs: R) -> Self::Output {
+                $method(self, rhs.into())
+            }
+        }
+
+        impl<T: Float> ops::$trait<Dual<T>> for i32 {
+            type Output = Dual<T>;
+
+            fn $method(self, rhs: Dual<T>) -> Self::Output {
+                $method(self.into(), rhs)
+            }
+        }
+    };
+}
+
+// Implements a binary operator with a float scalar on the left hand side, which can't be
+// done generically over `T` as the scalar type itself is foreign.
+macro_rules! impl_dual_scalar_op {
+    ($t:ty, $trait:ident, $method:ident) => {
+        impl ops::$trait<Dual<$t>> for $t {
+            type Output = Dual<$t>;
+
+            fn $method(self, rhs: Dual<$t>) -> Self::Output {
+                $method(self.into(), rhs)
+            }
+        }
+    };
+}
+
+impl_dual_op!(Add, add);
+impl_dual_op!(Sub, sub);
+impl_dual_op!(Mul, mul);
+impl_dual_op!(Div, div);
+
+impl_dual_scalar_op!(f32, Add, add);
+impl_dual_scalar_op!(f32, Sub, sub);
+impl_dual_scalar_op!(f32, Mul, mul);
+impl_dual_scalar_op!(f32, Div, div);
+
+impl_dual_scalar_op!(f64, Add, add);
+impl_dual_scalar_op!(f64, Sub, sub);
+impl_dual_scalar_op!(f64, Mul, mul);
+impl_dual_scalar_op!(f64, Div, div);
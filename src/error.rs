@@ -0,0 +1,79 @@
+//! Errors reported by the fallible `try_` variants of the graph and network functions.
+
+use std::fmt;
+
+/// Why a graph couldn't be evaluated or differentiated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A node whose operation can't take its number of children, e.g. an `Add` with one child.
+    Arity { op: String, children: usize },
+    /// A custom operation returned a number of local gradients different from its number of
+    /// children.
+    LocalGrads { op: String, children: usize, grads: usize },
+    /// A value which is, directly or not, one of its own children.
+    Cycle,
+    /// Inputs whose length doesn't match what they are combined with, e.g. the weights of a neuron.
+    ShapeMismatch { expected: usize, actual: usize },
+    /// Inputs of the wrong length for a layer of a `MultiLayerPerceptron`, counting from 0.
+    LayerShapeMismatch { layer: usize, expected: usize, actual: usize },
+    /// A value that was needed while borrowed elsewhere, e.g. by a guard held across the call.
+    ///
+    /// With the `sync` feature a value locked by another thread is also reported, while the
+    /// functions that panic instead of returning an error wait for the lock.
+    BorrowConflict,
+    /// A network without outputs, which can't be trained.
+    EmptyOutput,
+    /// A NaN or infinite value found while anomaly detection is enabled, see `crate::anomaly`.
+    ///
+    /// `problem` says what `op` produced, e.g. "a NaN output in the forward pass". Anomalies in
+    /// the backward pass also give the gradient of the output and the nodes from the root to it.
+    Anomaly {
+        op: String,
+        problem: String,
+        inputs: String,
+        grad: Option<String>,
+        path: Vec<String>,
+    },
+}
+
+pub type Result<R> = std::result::Result<R, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Arity { op, children } => {
+                write!(f, "`{}` can't be applied to {} children", op, children)
+            }
+            Error::LocalGrads { op, children, grads } => write!(
+                f,
+                "`{}` returned {} local gradients for {} children",
+                op, grads, children
+            ),
+            Error::Cycle => write!(f, "the graph has a cycle"),
+            Error::ShapeMismatch { expected, actual } => {
+                write!(f, "wrong number of inputs: expected {} but got {}", expected, actual)
+            }
+            Error::LayerShapeMismatch { layer, expected, actual } => write!(
+                f,
+                "wrong number of inputs for layer {}: expected {} but got {}",
+                layer, expected, actual
+            ),
+            Error::BorrowConflict => write!(f, "a value of the graph is already borrowed"),
+            Error::EmptyOutput => write!(f, "the network has no outputs"),
+            Error::Anomaly { op, problem, inputs, grad, path } => {
+                write!(f, "anomaly detected: `{}` produced {}\n  inputs: {}", op, problem, inputs)?;
+                if let Some(grad) = grad {
+                    write!(
+                        f,
+                        "\n  gradient of the output: {}\n  path from the root: {}",
+                        grad,
+                        path.join(" -> ")
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
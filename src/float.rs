@@ -0,0 +1,23 @@
+use std::fmt;
+
+/// Floating point types that can be used as the data of a `Value`.
+///
+/// Implemented for `f32`, which is the default everywhere, and `f64`.
+pub trait Float:
+    num_traits::Float + fmt::Debug + fmt::Display + Default + Send + Sync + 'static
+{
+    /// Converts an `f64` constant into this type, rounding if needed.
+    fn from_f64(value: f64) -> Self;
+}
+
+impl Float for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+}
+
+impl Float for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+}
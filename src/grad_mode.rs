@@ -0,0 +1,47 @@
+//! Switches controlling whether operations on values record a computation graph.
+
+use std::cell::Cell;
+
+thread_local! {
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Whether operations on this thread currently record their children.
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(Cell::get)
+}
+
+/// Stops operations on this thread from recording a graph until the guard is dropped.
+///
+/// While disabled every operation still computes its `data` but returns a leaf without
+/// children, so nothing is retained for back propagation. Dropping the guard restores
+/// the previous state, so guards can be nested.
+pub struct NoGradGuard {
+    previous: bool,
+}
+
+impl NoGradGuard {
+    pub fn new() -> NoGradGuard {
+        NoGradGuard {
+            previous: GRAD_ENABLED.with(|enabled| enabled.replace(false)),
+        }
+    }
+}
+
+impl Default for NoGradGuard {
+    fn default() -> Self {
+        NoGradGuard::new()
+    }
+}
+
+impl Drop for NoGradGuard {
+    fn drop(&mut self) {
+        GRAD_ENABLED.with(|enabled| enabled.set(self.previous));
+    }
+}
+
+/// Runs `f` without recording a graph, see `NoGradGuard`.
+pub fn no_grad<R, F: FnOnce() -> R>(f: F) -> R {
+    let _guard = NoGradGuard::new();
+    f()
+}
@@ -0,0 +1,140 @@
+use crate::float::Float;
+use crate::neuron::NetworkParameters;
+use crate::value::{RefValue, Value};
+
+/// Comparison of the back propagated and the numerical gradient of a single input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradCheckEntry<T: Float = f32> {
+    // Gradient computed by `Value::back_propagate`.
+    pub analytic: T,
+    // Gradient estimated with central finite differences.
+    pub numeric: T,
+    pub abs_error: T,
+    // Absolute error relative to the larger of the two gradients, 0 if both are 0.
+    pub rel_error: T,
+}
+
+/// Result of a gradient check with one entry per input, in the order of the inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradCheckReport<T: Float = f32> {
+    pub entries: Vec<GradCheckEntry<T>>,
+}
+
+impl<T: Float> GradCheckEntry<T> {
+    fn new(analytic: T, numeric: T) -> GradCheckEntry<T> {
+        let abs_error = (analytic - numeric).abs();
+        let scale = analytic.abs().max(numeric.abs());
+        let rel_error = if scale > T::zero() { abs_error / scale } else { T::zero() };
+
+        GradCheckEntry {
+            analytic,
+            numeric,
+            abs_error,
+            rel_error,
+        }
+    }
+}
+
+impl<T: Float> GradCheckReport<T> {
+    pub fn max_abs_error(&self) -> T {
+        self.entries
+            .iter()
+            .fold(T::zero(), |acc, entry| acc.max(entry.abs_error))
+    }
+
+    pub fn max_rel_error(&self) -> T {
+        self.entries
+            .iter()
+            .fold(T::zero(), |acc, entry| acc.max(entry.rel_error))
+    }
+
+    /// Whether every input satisfies `abs_error <= atol + rtol * |numeric|`.
+    pub fn passed(&self, atol: T, rtol: T) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.abs_error <= atol + rtol * entry.numeric.abs())
+    }
+}
+
+/// Checks the gradients of the graph built by `f` at `inputs` against central finite differences.
+///
+/// `f` is called once with leaves holding `inputs` to back propagate, then twice per input with
+/// that input shifted by `eps` in either direction.
+pub fn gradcheck<T, F>(f: F, inputs: &[T], eps: T) -> GradCheckReport<T>
+where
+    T: Float,
+    F: Fn(&[RefValue<T>]) -> RefValue<T>,
+{
+    let leaves: Vec<RefValue<T>> = inputs.iter().map(|&x| Value::leaf(x)).collect();
+    let output = f(&leaves);
+    // Inputs the output doesn't depend on aren't reset by the back propagation.
+    for leaf in &leaves {
+        leaf.get().borrow_mut().grad = T::zero();
+    }
+    Value::back_propagate(&output);
+
+    let evaluate = |index: usize, shift: T| {
+        let shifted: Vec<RefValue<T>> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| Value::leaf(if i == index { x + shift } else { x }))
+            .collect();
+        let output = f(&shifted);
+        let data = output.get().borrow().data;
+        data
+    };
+
+    let entries = leaves
+        .iter()
+        .enumerate()
+        .map(|(i, leaf)| {
+            let numeric = (evaluate(i, eps) - evaluate(i, -eps)) / (T::from_f64(2.0) * eps);
+            GradCheckEntry::new(leaf.get().borrow().grad, numeric)
+        })
+        .collect();
+
+    GradCheckReport { entries }
+}
+
+/// Checks the gradients of `loss` with respect to every parameter of `model`.
+///
+/// `loss` must build a new graph from the current data of the parameters each time it is
+/// called. Each parameter is shifted by `eps` in turn and restored afterwards.
+pub fn gradcheck_parameters<T, M, F>(model: &M, loss: F, eps: T) -> GradCheckReport<T>
+where
+    T: Float,
+    M: NetworkParameters<T> + ?Sized,
+    F: Fn() -> RefValue<T>,
+{
+    let output = loss();
+    // Parameters the loss doesn't depend on aren't reset by the back propagation.
+    model.zero_grad();
+    Value::back_propagate(&output);
+
+    let evaluate = || {
+        let output = loss();
+        let data = output.get().borrow().data;
+        data
+    };
+
+    let entries = model
+        .parameters()
+        .iter()
+        .map(|param| {
+            let (data, analytic) = {
+                let p = param.get().borrow();
+                (p.data, p.grad)
+            };
+
+            param.get().borrow_mut().data = data + eps;
+            let plus = evaluate();
+            param.get().borrow_mut().data = data - eps;
+            let minus = evaluate();
+            param.get().borrow_mut().data = data;
+
+            GradCheckEntry::new(analytic, (plus - minus) / (T::from_f64(2.0) * eps))
+        })
+        .collect();
+
+    GradCheckReport { entries }
+}
@@ -1,2 +1,14 @@
+pub mod anomaly;
+pub mod derivatives;
+pub mod dual;
+pub mod error;
+pub mod float;
+pub mod grad_mode;
+pub mod gradcheck;
 pub mod neuron;
-pub mod value;
\ No newline at end of file
+pub mod op;
+pub mod optimize;
+pub mod program;
+pub mod shared;
+pub mod tape;
+pub mod value;
@@ -1,9 +1,6 @@
-mod value;
-mod neuron;
-
-use value::{Value, RefValue};
-
-use crate::neuron::MultiLayerPerceptron;
+use micrograd_rs::grad_mode::no_grad;
+use micrograd_rs::neuron::MultiLayerPerceptron;
+use micrograd_rs::value::{RefValue, Value};
 
 fn main() {
     let xs = vec![
@@ -18,7 +15,10 @@ fn main() {
     let forward_and_print = |inputs: Vec<Vec<RefValue>>| {
         let mut combined_ypred = Vec::new();
         for input in inputs {
-            let ypred = mlp.forward(&input).iter().map(|val| val.get().borrow().data.to_string()).collect::<Vec<String>>();
+            let ypred = no_grad(|| mlp.forward(&input))
+                .iter()
+                .map(|val| val.get().borrow().data.to_string())
+                .collect::<Vec<String>>();
             combined_ypred.push(ypred.join(", "));
         }
         println!("Pre training forward: [{}]", combined_ypred.join(", "));
@@ -1,47 +1,150 @@
+#[cfg(feature = "sync")]
+use crate::anomaly::{is_anomaly_detection_enabled, AnomalyDetectionGuard};
+use crate::error::{Error, Result};
+use crate::float::Float;
+#[cfg(feature = "sync")]
+use crate::grad_mode::{is_grad_enabled, NoGradGuard};
+use crate::op::Op;
+use crate::program::Program;
+use crate::shared::{MaybeSendSync, Shared};
 use crate::value::{RefValue, Value};
 use rand::Rng;
 use std::fmt;
 use log::{debug};
 
-pub trait NetworkParameters {
-    fn parameters(&self) -> Vec<RefValue>;
+pub trait NetworkParameters<T: Float = f32> {
+    fn parameters(&self) -> Vec<RefValue<T>>;
+
+    /// Sets the gradient of every parameter to 0.
+    fn zero_grad(&self) {
+        for p in self.parameters() {
+            p.get().borrow_mut().grad = T::zero();
+        }
+    }
+}
+
+// Prepends `prefix` to the label of every parameter of `model`.
+fn prefix_labels<T: Float>(model: &impl NetworkParameters<T>, prefix: &str) {
+    for p in model.parameters() {
+        let mut p = p.get().borrow_mut();
+        let label = match p.label() {
+            Some(label) => format!("{}.{}", prefix, label),
+            None => prefix.to_string(),
+        };
+        p.set_label(label);
+    }
 }
 
+/// A function called with the inputs and outputs of a forward pass, see
+/// `Neuron::register_forward_hook` and `Layer::register_forward_hook`.
+///
+/// With the `sync` feature the function must also be `Send + Sync`.
+pub trait ForwardHook<T: Float>: Fn(&[RefValue<T>], &[RefValue<T>]) + MaybeSendSync {}
+
+impl<T: Float, F: Fn(&[RefValue<T>], &[RefValue<T>]) + MaybeSendSync> ForwardHook<T> for F {}
+
 #[derive(Clone)]
-pub struct Neuron {
-    pub weights: Vec<RefValue>,
-    pub bias: RefValue,
+pub struct Neuron<T: Float = f32> {
+    pub weights: Vec<RefValue<T>>,
+    pub bias: RefValue<T>,
+    forward_hooks: Vec<Shared<dyn ForwardHook<T>>>,
 }
 
 impl Neuron {
     pub fn new(len: usize) -> Neuron {
+        Neuron::random(len)
+    }
+}
+
+impl<T: Float> Neuron<T> {
+    /// Creates a neuron for any float type with weights and bias drawn uniformly from [-1, 1).
+    ///
+    /// The parameters are labeled `w0`, `w1`, ... and `b`.
+    pub fn random(len: usize) -> Neuron<T> {
         let mut rng = rand::thread_rng();
-        let mut n = Neuron {
-            weights: Vec::<RefValue>::with_capacity(len),
-            bias: Value::new(rng.gen_range(-1.0..1.0)),
+        let bias = Value::leaf(T::from_f64(rng.gen_range(-1.0..1.0))).with_label("b");
+        let weights = (0..len)
+            .map(|i| {
+                Value::leaf(T::from_f64(rng.gen_range(-1.0..1.0))).with_label(format!("w{}", i))
+            })
+            .collect();
+
+        Neuron::from_parameters(weights, bias)
+    }
+
+    /// Creates a neuron from existing parameters, e.g. trained elsewhere.
+    ///
+    /// Neurons hold their forward hooks in a private field, so this replaces building them
+    /// as a struct literal.
+    pub fn from_parameters(weights: Vec<RefValue<T>>, bias: RefValue<T>) -> Neuron<T> {
+        Neuron {
+            weights,
+            bias,
+            forward_hooks: vec![],
+        }
+    }
+
+    /// tanh of the weighted sum of `x` plus the bias.
+    ///
+    /// Panics on the errors `try_forward` returns.
+    pub fn forward(&self, x: &[RefValue<T>]) -> RefValue<T> {
+        self.forward_with(x, true).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `forward` but returns an error if `x` doesn't have one entry per weight.
+    pub fn try_forward(&self, x: &[RefValue<T>]) -> Result<RefValue<T>> {
+        self.forward_with(x, false)
+    }
+
+    // With `wait` set, as in `forward`, other threads holding an input or a parameter are
+    // waited for with the `sync` feature, otherwise they are reported, see `Value::try_apply`.
+    fn forward_with(&self, x: &[RefValue<T>], wait: bool) -> Result<RefValue<T>> {
+        if x.len() != self.weights.len() {
+            return Err(Error::ShapeMismatch {
+                expected: self.weights.len(),
+                actual: x.len(),
+            });
+        }
+        let apply = |op, children| {
+            if wait {
+                Ok(Value::apply(op, children))
+            } else {
+                Value::try_apply(op, children)
+            }
         };
+        let mut children = x.to_vec();
+        children.extend_from_slice(&self.weights);
+        let dot = apply(Op::Dot, children)?;
+        let weighted_sum = apply(Op::Add, vec![dot, self.bias.clone()])?;
 
-        for _ in 0..len {
-            let v = Value::new(rng.gen_range(-1.0..1.0));
-            n.weights.push(v);
+        let output = apply(Op::Tanh, vec![weighted_sum])?;
+        for hook in &self.forward_hooks {
+            hook(x, std::slice::from_ref(&output));
         }
+        Ok(output)
+    }
 
-        n
+    /// Number of inputs the neuron takes, one per weight.
+    pub fn in_features(&self) -> usize {
+        self.weights.len()
     }
 
-    pub fn forward(&self, x: &Vec<RefValue>) -> RefValue {
-        let weighted_sum: RefValue = x
-            .iter()
-            .zip(self.weights.iter())
-            .map(|(x_val, weight)| Value::mul(x_val.clone(), weight.clone()))
-            .fold(self.bias.clone(), |acc, v| Value::add(v, acc));
+    /// Number of outputs of the neuron, always 1.
+    pub fn out_features(&self) -> usize {
+        1
+    }
 
-        Value::tanh(weighted_sum)
+    /// Registers `hook` to be called with the inputs and the output of every `forward`,
+    /// e.g. to capture activations.
+    ///
+    /// `MultiLayerPerceptron::train` only runs `forward` once, when it traces its loss.
+    pub fn register_forward_hook(&mut self, hook: impl ForwardHook<T> + 'static) {
+        self.forward_hooks.push(Shared::new(hook));
     }
 }
 
-impl NetworkParameters for Neuron {
-    fn parameters(&self) -> Vec<RefValue> {
+impl<T: Float> NetworkParameters<T> for Neuron<T> {
+    fn parameters(&self) -> Vec<RefValue<T>> {
         let mut params = vec![self.bias.clone()];
         params.extend_from_slice(&self.weights);
 
@@ -50,33 +153,86 @@ impl NetworkParameters for Neuron {
 }
 
 #[derive(Clone)]
-pub struct Layer {
-    pub neurons: Vec<Neuron>,
+pub struct Layer<T: Float = f32> {
+    pub neurons: Vec<Neuron<T>>,
+    forward_hooks: Vec<Shared<dyn ForwardHook<T>>>,
 }
 
 impl Layer {
     pub fn new(len_in: usize, len_out: usize) -> Layer {
-        let mut layer = Layer {
-            neurons: Vec::with_capacity(len_out),
-        };
+        Layer::random(len_in, len_out)
+    }
+}
+
+impl<T: Float> Layer<T> {
+    /// Creates a layer for any float type, see `Neuron::random`.
+    ///
+    /// Parameters are labeled after their neuron, e.g. `neuron3.w2`.
+    pub fn random(len_in: usize, len_out: usize) -> Layer<T> {
+        let neurons = (0..len_out)
+            .map(|j| {
+                let neuron = Neuron::random(len_in);
+                prefix_labels(&neuron, &format!("neuron{}", j));
+                neuron
+            })
+            .collect();
 
-        for _ in 0..len_out {
-            layer.neurons.push(Neuron::new(len_in));
+        Layer::from_neurons(neurons)
+    }
+
+    /// Creates a layer from existing neurons, see `Neuron::from_parameters`.
+    pub fn from_neurons(neurons: Vec<Neuron<T>>) -> Layer<T> {
+        Layer {
+            neurons,
+            forward_hooks: vec![],
         }
+    }
 
-        layer
+    /// The output of every neuron for `x`.
+    ///
+    /// Panics on the errors `try_forward` returns.
+    pub fn forward(&self, x: &[RefValue<T>]) -> Vec<RefValue<T>> {
+        self.forward_with(x, true).unwrap_or_else(|err| panic!("{}", err))
     }
 
-    pub fn forward(&self, x: &Vec<RefValue>) -> Vec<RefValue> {
-        self.neurons
+    /// Like `forward` but returns an error if `x` doesn't fit one of the neurons, see
+    /// `Neuron::try_forward`.
+    pub fn try_forward(&self, x: &[RefValue<T>]) -> Result<Vec<RefValue<T>>> {
+        self.forward_with(x, false)
+    }
+
+    // See `Neuron::forward_with`.
+    fn forward_with(&self, x: &[RefValue<T>], wait: bool) -> Result<Vec<RefValue<T>>> {
+        let outputs = self
+            .neurons
             .iter()
-            .map(|neuron| neuron.forward(x))
-            .collect()
+            .map(|neuron| neuron.forward_with(x, wait))
+            .collect::<Result<Vec<RefValue<T>>>>()?;
+        for hook in &self.forward_hooks {
+            hook(x, &outputs);
+        }
+        Ok(outputs)
+    }
+
+    /// Number of inputs of the neurons, 0 for a layer without neurons.
+    pub fn in_features(&self) -> usize {
+        self.neurons.first().map_or(0, Neuron::in_features)
+    }
+
+    /// Number of outputs of the layer, one per neuron.
+    pub fn out_features(&self) -> usize {
+        self.neurons.len()
+    }
+
+    /// Registers `hook` to be called with the inputs and the outputs of every `forward`,
+    /// e.g. to capture activations.
+    pub fn register_forward_hook(&mut self, hook: impl ForwardHook<T> + 'static) {
+        self.forward_hooks.push(Shared::new(hook));
     }
 }
 
-impl NetworkParameters for Layer {
-    fn parameters(&self) -> Vec<RefValue> {
+impl<T: Float> NetworkParameters<T> for Layer<T> {
+    fn parameters(&self) -> Vec<RefValue<T>> {
         self.neurons
             .iter()
             .flat_map(|neuron| neuron.parameters())
@@ -85,77 +241,296 @@ impl NetworkParameters for Layer {
 }
 
 #[derive(Clone)]
-pub struct MultiLayerPerceptron {
-    pub layers: Vec<Layer>,
+pub struct MultiLayerPerceptron<T: Float = f32> {
+    pub layers: Vec<Layer<T>>,
 }
 
 impl MultiLayerPerceptron {
     pub fn new(len_in: usize, len_outs: Vec<usize>) -> MultiLayerPerceptron {
+        MultiLayerPerceptron::random(len_in, len_outs)
+    }
+}
+
+impl<T: Float> MultiLayerPerceptron<T> {
+    /// Creates a network for any float type, see `Neuron::random`.
+    ///
+    /// Parameters are labeled after their layer and neuron, counting from 0, e.g.
+    /// `layer1.neuron3.w2` is `layers[1].neurons[3].weights[2]`.
+    pub fn random(len_in: usize, len_outs: Vec<usize>) -> MultiLayerPerceptron<T> {
         let mut mlp = MultiLayerPerceptron {
-            layers: Vec::<Layer>::with_capacity(len_outs.len()),
+            layers: Vec::<Layer<T>>::with_capacity(len_outs.len()),
         };
 
         let mut layer_sizes = vec![len_in];
         layer_sizes.extend_from_slice(&len_outs);
 
         for i in 0..(layer_sizes.len() - 1) {
-            mlp.layers
-                .push(Layer::new(layer_sizes[i], layer_sizes[i + 1]));
+            let layer = Layer::random(layer_sizes[i], layer_sizes[i + 1]);
+            prefix_labels(&layer, &format!("layer{}", i));
+            mlp.layers.push(layer);
         }
 
         mlp
     }
 
-    pub fn forward(&self, x: &Vec<RefValue>) -> Vec<RefValue> {
+    /// Passes `x` through every layer in turn.
+    ///
+    /// Panics on the errors `try_forward` returns.
+    pub fn forward(&self, x: &[RefValue<T>]) -> Vec<RefValue<T>> {
+        self.forward_with(x, true).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `forward` but returns an error if `x` doesn't fit the network, naming the layer
+    /// whose input has the wrong length, see `Layer::try_forward`.
+    pub fn try_forward(&self, x: &[RefValue<T>]) -> Result<Vec<RefValue<T>>> {
+        self.forward_with(x, false)
+    }
+
+    // See `Neuron::forward_with`.
+    fn forward_with(&self, x: &[RefValue<T>], wait: bool) -> Result<Vec<RefValue<T>>> {
         let mut out = x.to_vec();
-        for layer in self.layers.iter() {
-            let res = layer.forward(&out);
+        for (index, layer) in self.layers.iter().enumerate() {
+            let res = layer.forward_with(&out, wait).map_err(|err| match err {
+                Error::ShapeMismatch { expected, actual } => Error::LayerShapeMismatch {
+                    layer: index,
+                    expected,
+                    actual,
+                },
+                err => err,
+            })?;
             out = res
         }
 
-        out
+        Ok(out)
+    }
+
+    /// Number of inputs of the first layer, 0 for a network without layers.
+    pub fn in_features(&self) -> usize {
+        self.layers.first().map_or(0, Layer::in_features)
     }
 
+    /// Number of outputs of the last layer, 0 for a network without layers.
+    pub fn out_features(&self) -> usize {
+        self.layers.last().map_or(0, Layer::out_features)
+    }
+
+    /// Fits the first output of the network to `ys` by gradient descent on the mean squared error.
+    ///
+    /// Panics on the errors `try_train` returns.
     pub fn train(
         &self,
-        learning_rate: f32,
+        learning_rate: T,
         iterations: u32,
-        xs: Vec<Vec<RefValue>>,
-        ys: Vec<RefValue>,
+        xs: Vec<Vec<RefValue<T>>>,
+        ys: Vec<RefValue<T>>,
     ) {
-        //let mut loss: RefValue = Value::new(0.0);
+        self.train_with(learning_rate, iterations, xs, ys, true)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    /// Like `train` but returns an error if there isn't one target in `ys` for each input of
+    /// `xs`, an input doesn't fit the network, the network has no outputs or a parameter
+    /// is borrowed.
+    pub fn try_train(
+        &self,
+        learning_rate: T,
+        iterations: u32,
+        xs: Vec<Vec<RefValue<T>>>,
+        ys: Vec<RefValue<T>>,
+    ) -> Result<()> {
+        self.train_with(learning_rate, iterations, xs, ys, false)
+    }
+
+    // See `Neuron::forward_with`.
+    fn train_with(
+        &self,
+        learning_rate: T,
+        iterations: u32,
+        xs: Vec<Vec<RefValue<T>>>,
+        ys: Vec<RefValue<T>>,
+        wait: bool,
+    ) -> Result<()> {
+        if xs.len() != ys.len() {
+            return Err(Error::ShapeMismatch {
+                expected: xs.len(),
+                actual: ys.len(),
+            });
+        }
+
+        let mut errors = Vec::with_capacity(ys.len());
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let ypred = self.forward_with(x, wait)?.first().cloned().ok_or(Error::EmptyOutput)?;
+            // (y-ypred)^2.0
+            errors.push(Value::pow(y.clone() - ypred, T::from_f64(2.0)));
+        }
+        let loss = errors.into_iter().sum::<RefValue<T>>();
+        let loss = Value::div(loss.clone(), Value::leaf(T::from_f64(ys.len() as f64)));
+
+        // The graph is the same every iteration, only the parameters change, so it is traced
+        // once and replayed.
+        let mut program = Program::compile(&loss, &[]);
+        let params = self.parameters();
         for iter in 0..iterations {
-            let loss = xs
-                .iter()
-                .map(|x| self.forward(x))
-                .map(|y| y.get(0).unwrap().clone())
-                .zip(ys.iter())
-                .fold(Value::new(0.0), |acc, (ypred, y)| {
-                    // acc + (y-ypref)^2.0
-                    acc + Value::pow(y.clone() - ypred, 2.0)
-                });
-            let loss = Value::div(loss.clone(), Value::new(ys.len() as f32));
-
-            Value::back_propagate(&loss);
+            let loss = program.forward_with(&[], wait)?;
+            program.back_propagate_with(wait)?;
             debug!("Loss at iteration {}: {}", iter, loss);
 
-            let params = self.parameters();
-            for p in params {
-                Value::backward(&p, learning_rate);
+            for p in &params {
+                let mut p = p.try_borrow_mut(wait)?;
+                p.data = p.data - learning_rate * p.grad;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T: Float> MultiLayerPerceptron<T> {
+    /// Runs `forward` over a batch of inputs, splitting the batch between threads.
+    ///
+    /// The threads record a graph only if the caller does, see `no_grad`, and check for
+    /// anomalies only if the caller does, see `detect_anomaly`.
+    pub fn par_forward(&self, xs: &[Vec<RefValue<T>>]) -> Vec<Vec<RefValue<T>>> {
+        par_chunks(xs, |chunk| chunk.iter().map(|x| self.forward(x)).collect::<Vec<_>>())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Back propagates the sum of `loss` over a batch, splitting the batch between threads.
+    ///
+    /// Each thread works on its own copy of the parameters so that the backward passes don't
+    /// interfere, the per-thread gradients are then summed into the `grad` of `parameters()`.
+    /// Returns the total loss.
+    ///
+    /// Panics if there isn't one target in `ys` for each input of `xs`, like `train`.
+    pub fn par_back_propagate<F>(&self, xs: &[Vec<RefValue<T>>], ys: &[RefValue<T>], loss: F) -> T
+    where
+        F: Fn(&[RefValue<T>], &RefValue<T>) -> RefValue<T> + Sync,
+    {
+        if xs.len() != ys.len() {
+            let err = Error::ShapeMismatch {
+                expected: xs.len(),
+                actual: ys.len(),
+            };
+            panic!("{}", err);
+        }
+        let samples: Vec<(&Vec<RefValue<T>>, &RefValue<T>)> = xs.iter().zip(ys.iter()).collect();
+        let partials = par_chunks(&samples, |chunk| {
+            let replica = self.replicate();
+            let total = chunk
+                .iter()
+                .map(|(x, y)| {
+                    let x: Vec<RefValue<T>> = x.iter().map(RefValue::detach).collect();
+                    loss(&replica.forward(&x), &y.detach())
+                })
+                .sum::<RefValue<T>>();
+            Value::back_propagate(&total);
+
+            let grads: Vec<T> = replica
+                .parameters()
+                .iter()
+                .map(|p| p.get().borrow().grad)
+                .collect();
+            let total = total.get().borrow().data;
+            (total, grads)
+        });
+
+        let params = self.parameters();
+        for p in &params {
+            p.get().borrow_mut().grad = T::zero();
+        }
+
+        let mut total_loss = T::zero();
+        for (partial_loss, grads) in partials {
+            total_loss = total_loss + partial_loss;
+            for (p, grad) in params.iter().zip(grads) {
+                let mut p = p.get().borrow_mut();
+                p.grad = p.grad + grad;
+            }
+        }
+        // The copies don't have the hooks of the parameters, which see the gradient of the
+        // whole batch instead, as they would in `Value::back_propagate`.
+        for p in &params {
+            let hooks = p.get().borrow().hooks().clone();
+            if !hooks.is_empty() {
+                let grad = hooks.apply(p.get().borrow().grad);
+                p.get().borrow_mut().grad = grad;
             }
         }
+        total_loss
+    }
+
+    // Copies the network into fresh leaf values that aren't shared with `self`.
+    fn replicate(&self) -> MultiLayerPerceptron<T> {
+        MultiLayerPerceptron {
+            layers: self
+                .layers
+                .iter()
+                .map(|layer| Layer {
+                    neurons: layer
+                        .neurons
+                        .iter()
+                        .map(|neuron| Neuron {
+                            weights: neuron.weights.iter().map(RefValue::detach).collect(),
+                            bias: neuron.bias.detach(),
+                            forward_hooks: neuron.forward_hooks.clone(),
+                        })
+                        .collect(),
+                    forward_hooks: layer.forward_hooks.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Applies `f` to roughly equal chunks of `items` on separate threads, returning the results in
+// order.
+#[cfg(feature = "sync")]
+fn par_chunks<I, R, F>(items: &[I], f: F) -> Vec<R>
+where
+    I: Sync,
+    R: Send,
+    F: Fn(&[I]) -> R + Sync,
+{
+    if items.is_empty() {
+        return vec![];
     }
+
+    // The grad mode and anomaly detection are per thread, so the caller's are set again in
+    // each worker.
+    let grad_enabled = is_grad_enabled();
+    let anomaly_detection = is_anomaly_detection_enabled();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = items.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(|| {
+                    let _no_grad = (!grad_enabled).then(NoGradGuard::new);
+                    let _anomaly = anomaly_detection.then(AnomalyDetectionGuard::new);
+                    f(chunk)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            // Panics of the workers, e.g. from anomaly detection, are passed on unchanged.
+            .map(|handle| handle.join().unwrap_or_else(|err| std::panic::resume_unwind(err)))
+            .collect()
+    })
 }
 
-impl NetworkParameters for MultiLayerPerceptron {
-    fn parameters(&self) -> Vec<RefValue> {
+impl<T: Float> NetworkParameters<T> for MultiLayerPerceptron<T> {
+    fn parameters(&self) -> Vec<RefValue<T>> {
         self.layers
             .iter()
             .flat_map(|layer| layer.parameters())
             .collect()
     }
 }
-impl fmt::Display for MultiLayerPerceptron {
+impl<T: Float> fmt::Display for MultiLayerPerceptron<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "MultiLayerPerceptron")?;
         for layer in self.layers.iter() {
@@ -165,7 +540,7 @@ impl fmt::Display for MultiLayerPerceptron {
     }
 }
 
-impl fmt::Display for Layer {
+impl<T: Float> fmt::Display for Layer<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Layer")?;
         for neuron in &self.neurons {
@@ -175,7 +550,7 @@ impl fmt::Display for Layer {
     }
 }
 
-impl fmt::Display for Neuron {
+impl<T: Float> fmt::Display for Neuron<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Neuron")?;
         for param in self.parameters().iter() {
@@ -0,0 +1,475 @@
+use crate::error::{Error, Result};
+use crate::float::Float;
+use crate::shared::{MaybeSendSync, Shared};
+use crate::value::{RefValue, Value};
+use std::fmt;
+
+/// A user-defined differentiable operation.
+///
+/// Implement this to add functions that are not built in (e.g. softsign or
+/// domain-specific kernels) and apply them with `Value::custom`. The resulting
+/// node takes part in back propagation and graph printing like any built-in op.
+/// With the `sync` feature the operation must also be `Send + Sync`.
+pub trait CustomOp<T: Float = f32>: fmt::Debug + MaybeSendSync {
+    /// Short name of the operation used when printing a graph.
+    fn name(&self) -> &str;
+
+    /// Computes the output of the operation given the data of its inputs.
+    fn forward(&self, inputs: &[T]) -> T;
+
+    /// Computes the derivative of the output with respect to each input,
+    /// returning one entry per input in the same order.
+    fn local_grads(&self, inputs: &[T], output: T) -> Vec<T>;
+
+    /// Builds the derivative of the output with respect to each input as a graph, which is
+    /// needed to differentiate through this operation more than once (see `Value::gradients`).
+    ///
+    /// Returning `None`, the default, uses the values of `local_grads` as constants, so first
+    /// derivatives are exact but higher derivatives through this operation are treated as zero.
+    fn local_grad_values(
+        &self,
+        _inputs: &[RefValue<T>],
+        _output: &RefValue<T>,
+    ) -> Option<Vec<RefValue<T>>> {
+        None
+    }
+}
+
+/// The operation that produced a non-leaf `Value`.
+///
+/// Each variant knows how to compute its forward result from the data of its
+/// children and the local gradient of that result with respect to each child,
+/// so the backward pass never has to special-case individual operations.
+#[derive(Debug, Clone)]
+pub enum Op<T: Float = f32> {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    // Raises the single child to a constant exponent which is not part of the graph.
+    Pow(T),
+    // Raises the first child to the power of the second child.
+    PowValue,
+    Tanh,
+    Exp,
+    Relu,
+    Log,
+    Sqrt,
+    Abs,
+    Neg,
+    Sin,
+    Cos,
+    Sigmoid,
+    Softplus,
+    // Relu with the given slope for negative inputs.
+    LeakyRelu(T),
+    // Exponential linear unit with the given alpha.
+    Elu(T),
+    // Gaussian error linear unit, using the tanh approximation.
+    Gelu,
+    Silu,
+    // Clamps the single child between a constant min and max.
+    Clamp(T, T),
+    Min,
+    Max,
+    // Sum of any number of children.
+    Sum,
+    // Product of any number of children.
+    Prod,
+    // Dot product of the first half of the children with the second half.
+    Dot,
+    // log(e^x1 + ... + e^xn) of any number of children.
+    LogSumExp,
+    // The entry at the given index of the softmax of all children.
+    Softmax(usize),
+    // The entry at the given index of the log-softmax of all children.
+    LogSoftmax(usize),
+    Custom(Shared<dyn CustomOp<T>>),
+}
+
+// sqrt(2 / pi) and the cubic coefficient of the tanh approximation of gelu.
+const GELU_K: f64 = 0.797_884_560_802_865_4;
+const GELU_C: f64 = 0.044_715;
+
+impl<T: Float> Op<T> {
+    /// Computes the output of the operation given the data of its children.
+    pub fn forward(&self, inputs: &[T]) -> T {
+        // The input of unary operations, n-ary operations may have no inputs at all.
+        let x = inputs.first().copied().unwrap_or_else(T::zero);
+        match self {
+            Op::Add => inputs[0] + inputs[1],
+            Op::Sub => inputs[0] - inputs[1],
+            Op::Mul => inputs[0] * inputs[1],
+            Op::Div => inputs[0] / inputs[1],
+            Op::Pow(n) => x.powf(*n),
+            Op::PowValue => inputs[0].powf(inputs[1]),
+            Op::Tanh => tanh(x),
+            Op::Exp => x.exp(),
+            Op::Relu => x.max(T::zero()),
+            Op::Log => x.ln(),
+            Op::Sqrt => x.sqrt(),
+            Op::Abs => x.abs(),
+            Op::Neg => -x,
+            Op::Sin => x.sin(),
+            Op::Cos => x.cos(),
+            Op::Sigmoid => sigmoid(x),
+            // log(1 + e^x) rewritten so that e^x can't overflow.
+            Op::Softplus => x.max(T::zero()) + (-x.abs()).exp().ln_1p(),
+            Op::LeakyRelu(alpha) => if x >= T::zero() { x } else { *alpha * x },
+            Op::Elu(alpha) => if x > T::zero() { x } else { *alpha * x.exp_m1() },
+            Op::Gelu => {
+                let u = T::from_f64(GELU_K) * (x + T::from_f64(GELU_C) * x.powi(3));
+                T::from_f64(0.5) * x * (T::one() + tanh(u))
+            }
+            Op::Silu => x * sigmoid(x),
+            Op::Clamp(min, max) => num_traits::clamp(x, *min, *max),
+            Op::Min => inputs[0].min(inputs[1]),
+            Op::Max => inputs[0].max(inputs[1]),
+            Op::Sum => inputs.iter().fold(T::zero(), |acc, &x| acc + x),
+            Op::Prod => inputs.iter().fold(T::one(), |acc, &x| acc * x),
+            Op::Dot => {
+                let (xs, ws) = inputs.split_at(inputs.len() / 2);
+                xs.iter().zip(ws).fold(T::zero(), |acc, (&x, &w)| acc + x * w)
+            }
+            Op::LogSumExp => logsumexp(inputs),
+            Op::Softmax(i) => (inputs[*i] - logsumexp(inputs)).exp(),
+            Op::LogSoftmax(i) => inputs[*i] - logsumexp(inputs),
+            Op::Custom(op) => op.forward(inputs),
+        }
+    }
+
+    /// Computes the derivative of the output with respect to each child.
+    ///
+    /// `inputs` is the data of the children and `output` the data produced by `forward`.
+    /// The returned vector has one entry per child, in the same order.
+    pub fn local_grads(&self, inputs: &[T], output: T) -> Vec<T> {
+        let x = inputs.first().copied().unwrap_or_else(T::zero);
+        let indicator = |condition: bool| if condition { T::one() } else { T::zero() };
+        match self {
+            Op::Add => vec![T::one(), T::one()],
+            Op::Sub => vec![T::one(), -T::one()],
+            Op::Mul => vec![inputs[1], inputs[0]],
+            Op::Div => vec![inputs[1].recip(), -output / inputs[1]],
+            Op::Pow(n) => vec![*n * x.powf(*n - T::one())],
+            // The exponent doesn't change a zero output, this also avoids ln(0) for a zero base.
+            Op::PowValue if output == T::zero() => {
+                vec![inputs[1] * inputs[0].powf(inputs[1] - T::one()), T::zero()]
+            }
+            Op::PowValue => vec![
+                inputs[1] * inputs[0].powf(inputs[1] - T::one()),
+                output * inputs[0].ln(),
+            ],
+            Op::Tanh => vec![T::one() - output.powi(2)],
+            Op::Exp => vec![output],
+            Op::Relu => vec![indicator(x >= T::zero())],
+            Op::Log => vec![x.recip()],
+            Op::Sqrt => vec![T::from_f64(0.5) / output],
+            Op::Abs => vec![indicator(x > T::zero()) - indicator(x < T::zero())],
+            Op::Neg => vec![-T::one()],
+            Op::Sin => vec![x.cos()],
+            Op::Cos => vec![-x.sin()],
+            Op::Sigmoid => vec![output * (T::one() - output)],
+            Op::Softplus => vec![sigmoid(x)],
+            Op::LeakyRelu(alpha) => vec![if x >= T::zero() { T::one() } else { *alpha }],
+            Op::Elu(alpha) => vec![if x > T::zero() { T::one() } else { output + *alpha }],
+            Op::Gelu => {
+                let (k, c) = (T::from_f64(GELU_K), T::from_f64(GELU_C));
+                let t = tanh(k * (x + c * x.powi(3)));
+                let half = T::from_f64(0.5);
+                vec![
+                    half * (T::one() + t)
+                        + half
+                            * x
+                            * (T::one() - t * t)
+                            * k
+                            * (T::one() + T::from_f64(3.0) * c * x * x),
+                ]
+            }
+            Op::Silu => {
+                let s = sigmoid(x);
+                vec![s * (T::one() + x * (T::one() - s))]
+            }
+            Op::Clamp(min, max) => vec![indicator(x >= *min && x <= *max)],
+            Op::Min => vec![indicator(inputs[0] <= inputs[1]), indicator(inputs[0] > inputs[1])],
+            Op::Max => vec![indicator(inputs[0] >= inputs[1]), indicator(inputs[0] < inputs[1])],
+            Op::Sum => vec![T::one(); inputs.len()],
+            Op::Prod => products_of_others(inputs, T::one(), |a, b| a * b),
+            Op::Dot => {
+                // Each x is scaled by the matching w and the other way around.
+                let (xs, ws) = inputs.split_at(inputs.len() / 2);
+                ws.iter().chain(xs).copied().collect()
+            }
+            Op::LogSumExp => logsumexp_grads(inputs, output),
+            // With s the softmax, d s_i / d x_j = s_i * (1[i = j] - s_j).
+            Op::Softmax(i) => {
+                let lse = logsumexp(inputs);
+                inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &x)| output * (indicator(j == *i) - (x - lse).exp()))
+                    .collect()
+            }
+            // d log(s_i) / d x_j = 1[i = j] - s_j.
+            Op::LogSoftmax(i) => {
+                let lse = logsumexp(inputs);
+                inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &x)| indicator(j == *i) - (x - lse).exp())
+                    .collect()
+            }
+            Op::Custom(op) => op.local_grads(inputs, output),
+        }
+    }
+
+    /// Builds the derivative of the output with respect to each child as a graph.
+    ///
+    /// Like `local_grads` but the results are differentiable values built from `children` and
+    /// `output`, which is what allows taking derivatives of derivatives.
+    pub fn local_grad_values(
+        &self,
+        children: &[RefValue<T>],
+        output: &RefValue<T>,
+    ) -> Vec<RefValue<T>> {
+        let x = || children[0].clone();
+        let one = || Value::leaf(T::one());
+        match self {
+            Op::Add => vec![one(), one()],
+            Op::Sub => vec![one(), Value::leaf(-T::one())],
+            Op::Mul => vec![children[1].clone(), children[0].clone()],
+            Op::Div => vec![
+                one() / children[1].clone(),
+                -output.clone() / children[1].clone(),
+            ],
+            Op::Pow(n) => vec![Value::pow(x(), *n - T::one()) * *n],
+            Op::PowValue if output.get().borrow().data == T::zero() => vec![
+                children[1].clone() * Value::pow_value(x(), children[1].clone() - one()),
+                Value::leaf(T::zero()),
+            ],
+            Op::PowValue => vec![
+                children[1].clone() * Value::pow_value(x(), children[1].clone() - one()),
+                output.clone() * Value::log(x()),
+            ],
+            Op::Tanh => vec![one() - Value::pow(output.clone(), T::from_f64(2.0))],
+            Op::Exp => vec![output.clone()],
+            Op::Log => vec![Value::pow(x(), -T::one())],
+            Op::Sqrt => vec![Value::pow(output.clone(), -T::one()) * T::from_f64(0.5)],
+            Op::Sin => vec![Value::cos(x())],
+            Op::Cos => vec![Value::neg(Value::sin(x()))],
+            Op::Sigmoid => vec![output.clone() * (one() - output.clone())],
+            Op::Softplus => vec![Value::sigmoid(x())],
+            Op::Elu(alpha) if x().get().borrow().data <= T::zero() => vec![output.clone() + *alpha],
+            Op::Gelu => {
+                let (k, c) = (T::from_f64(GELU_K), T::from_f64(GELU_C));
+                let t = Value::tanh((x() + Value::pow(x(), T::from_f64(3.0)) * c) * k);
+                let half = T::from_f64(0.5);
+                let dt = (one() - Value::pow(t.clone(), T::from_f64(2.0)))
+                    * (one() + Value::pow(x(), T::from_f64(2.0)) * (T::from_f64(3.0) * c))
+                    * k;
+                vec![(one() + t) * half + x() * dt * half]
+            }
+            Op::Silu => {
+                let s = Value::sigmoid(x());
+                vec![s.clone() * (one() + x() * (one() - s))]
+            }
+            Op::Sum => vec![one(); children.len()],
+            Op::Prod => products_of_others(children, one(), Value::mul),
+            Op::Dot => {
+                let (xs, ws) = children.split_at(children.len() / 2);
+                ws.iter().chain(xs).cloned().collect()
+            }
+            // An infinite output is flat in the inputs, see `logsumexp_grads`.
+            Op::LogSumExp if output.get().borrow().data.is_infinite() => {
+                self.constant_local_grads(children, output)
+            }
+            Op::LogSumExp => children
+                .iter()
+                .map(|child| Value::exp(child.clone() - output.clone()))
+                .collect(),
+            Op::Softmax(i) => Value::softmax(children)
+                .into_iter()
+                .enumerate()
+                .map(|(j, p)| {
+                    if j == *i {
+                        output.clone() * (one() - p)
+                    } else {
+                        Value::neg(output.clone() * p)
+                    }
+                })
+                .collect(),
+            Op::LogSoftmax(i) => Value::softmax(children)
+                .into_iter()
+                .enumerate()
+                .map(|(j, p)| if j == *i { one() - p } else { Value::neg(p) })
+                .collect(),
+            // The remaining derivatives are piecewise constant.
+            Op::Relu
+            | Op::Abs
+            | Op::Neg
+            | Op::LeakyRelu(_)
+            | Op::Elu(_)
+            | Op::Clamp(_, _)
+            | Op::Min
+            | Op::Max => self.constant_local_grads(children, output),
+            Op::Custom(op) => op
+                .local_grad_values(children, output)
+                .unwrap_or_else(|| self.constant_local_grads(children, output)),
+        }
+    }
+
+    // Wraps the values of `local_grads` into leaves which don't depend on the children.
+    fn constant_local_grads(
+        &self,
+        children: &[RefValue<T>],
+        output: &RefValue<T>,
+    ) -> Vec<RefValue<T>> {
+        let inputs: Vec<T> = children.iter().map(|c| c.get().borrow().data).collect();
+        let output = output.get().borrow().data;
+        self.local_grads(&inputs, output)
+            .into_iter()
+            .map(Value::leaf)
+            .collect()
+    }
+
+    /// Checks that the operation can be applied to `children` children.
+    ///
+    /// Custom operations accept any number of children.
+    pub fn check_arity(&self, children: usize) -> Result<()> {
+        let valid = match self {
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::PowValue | Op::Min | Op::Max => {
+                children == 2
+            }
+            Op::Sum | Op::Prod | Op::LogSumExp | Op::Custom(_) => true,
+            Op::Dot => children.is_multiple_of(2),
+            Op::Softmax(i) | Op::LogSoftmax(i) => *i < children,
+            _ => children == 1,
+        };
+        if !valid {
+            return Err(Error::Arity {
+                op: self.name().to_string(),
+                children,
+            });
+        }
+        Ok(())
+    }
+
+    /// Short name of the operation used when printing a graph.
+    pub fn name(&self) -> &str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Pow(_) | Op::PowValue => "pow",
+            Op::Tanh => "tanh",
+            Op::Exp => "exp",
+            Op::Relu => "relu",
+            Op::Log => "log",
+            Op::Sqrt => "sqrt",
+            Op::Abs => "abs",
+            Op::Neg => "neg",
+            Op::Sin => "sin",
+            Op::Cos => "cos",
+            Op::Sigmoid => "sigmoid",
+            Op::Softplus => "softplus",
+            Op::LeakyRelu(_) => "leaky_relu",
+            Op::Elu(_) => "elu",
+            Op::Gelu => "gelu",
+            Op::Silu => "silu",
+            Op::Clamp(_, _) => "clamp",
+            Op::Min => "min",
+            Op::Max => "max",
+            Op::Sum => "sum",
+            Op::Prod => "prod",
+            Op::Dot => "dot",
+            Op::LogSumExp => "logsumexp",
+            Op::Softmax(_) => "softmax",
+            Op::LogSoftmax(_) => "log_softmax",
+            Op::Custom(op) => op.name(),
+        }
+    }
+}
+
+fn tanh<T: Float>(x: T) -> T {
+    // As `exp` can return infinity which then later converting to tanh can change it to NaN
+    // to fix this we bound the number between Max and Min of the float type
+    let e2x = num_traits::clamp((T::from_f64(2.0) * x).exp(), T::min_value(), T::max_value());
+    (e2x - T::one()) / (e2x + T::one())
+}
+
+// Computes for every item the product of all the other items, in O(n) and without dividing
+// so that zeros are handled. `one` is the multiplicative identity.
+fn products_of_others<V: Clone>(items: &[V], one: V, mul: impl Fn(V, V) -> V) -> Vec<V> {
+    let mut products = Vec::with_capacity(items.len());
+    let mut prefix = one.clone();
+    for item in items {
+        products.push(prefix.clone());
+        prefix = mul(prefix, item.clone());
+    }
+
+    let mut suffix = one;
+    for (product, item) in products.iter_mut().zip(items).rev() {
+        *product = mul(product.clone(), suffix.clone());
+        suffix = mul(suffix, item.clone());
+    }
+    products
+}
+
+fn logsumexp<T: Float>(inputs: &[T]) -> T {
+    // Subtracting the largest input first means `exp` is only ever given numbers <= 0 and
+    // can't overflow, while at least one of the terms is exactly 1.
+    let max = inputs.iter().fold(T::neg_infinity(), |acc, &x| acc.max(x));
+    if !max.is_finite() {
+        return max;
+    }
+
+    max + inputs.iter().fold(T::zero(), |acc, &x| acc + (x - max).exp()).ln()
+}
+
+// The softmax of `inputs`, which is the derivative of their logsumexp `output`. For an
+// infinite output, e.g. when every input is -inf, `x - output` is NaN for the inputs equal to
+// it, so these share the weight equally instead, which is the limit for finite inputs.
+fn logsumexp_grads<T: Float>(inputs: &[T], output: T) -> Vec<T> {
+    if !output.is_infinite() {
+        return inputs.iter().map(|&x| (x - output).exp()).collect();
+    }
+
+    let count = inputs.iter().filter(|&&x| x == output).count();
+    let weight = T::one() / T::from_f64(count as f64);
+    inputs
+        .iter()
+        .map(|&x| if x == output { weight } else { T::zero() })
+        .collect()
+}
+
+fn sigmoid<T: Float>(x: T) -> T {
+    // Only ever exponentiate a non-positive number so that `exp` can't overflow.
+    if x >= T::zero() {
+        T::one() / (T::one() + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (T::one() + e)
+    }
+}
+
+impl<T: Float> PartialEq for Op<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Op::Pow(a), Op::Pow(b))
+            | (Op::LeakyRelu(a), Op::LeakyRelu(b))
+            | (Op::Elu(a), Op::Elu(b)) => a == b,
+            (Op::Clamp(a_min, a_max), Op::Clamp(b_min, b_max)) => a_min == b_min && a_max == b_max,
+            (Op::Softmax(a), Op::Softmax(b)) | (Op::LogSoftmax(a), Op::LogSoftmax(b)) => a == b,
+            // Custom operations are only equal if they are the same registered instance.
+            (Op::Custom(a), Op::Custom(b)) => Shared::ptr_eq(a, b),
+            // All other variants carry no data.
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl<T: Float> fmt::Display for Op<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
@@ -0,0 +1,197 @@
+use crate::float::Float;
+use crate::op::Op;
+use crate::value::{RefValue, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Returns a smaller graph computing the same value as `root`.
+///
+/// Every leaf other than `variables` is treated as a constant. Operations on constants only are
+/// folded into a single leaf, operations returning one of their inputs unchanged (`x * 1`,
+/// `x + 0`, `x / 1`, ...) are removed, and nodes applying the same operation to the same
+/// children are merged into one.
+///
+/// The result uses the same `variables`, so back propagating through it gives them the same
+/// gradients as back propagating through `root`. To keep that true, nodes with gradient hooks
+/// are never removed or merged, and keep their hooks when their children change. The
+/// original graph isn't modified, nodes which don't need to change are shared with it.
+#[allow(clippy::mutable_key_type)]
+pub fn optimize<T: Float>(root: &RefValue<T>, variables: &[RefValue<T>]) -> RefValue<T> {
+    let mut topo = vec![];
+    let mut visited = HashSet::new();
+    Value::topological_sort(root, &mut topo, &mut visited);
+
+    let mut pass = Pass {
+        variables: variables.iter().cloned().collect(),
+        optimized: HashMap::new(),
+        ids: HashMap::new(),
+        constants: HashMap::new(),
+        common: HashMap::new(),
+    };
+    for node in &topo {
+        let optimized = pass.optimize_node(node);
+        pass.optimized.insert(node.clone(), optimized);
+    }
+
+    pass.optimized[root].clone()
+}
+
+// State of an optimization, the nodes of the original graph are visited children first.
+struct Pass<T: Float> {
+    variables: HashSet<RefValue<T>>,
+    // The node replacing each node of the original graph.
+    optimized: HashMap<RefValue<T>, RefValue<T>>,
+    // A number for each node of the optimized graph, used to identify children.
+    ids: HashMap<RefValue<T>, usize>,
+    // One leaf per distinct constant, keyed by the bits of its data.
+    constants: HashMap<u64, RefValue<T>>,
+    // Nodes of the optimized graph by op name and children, to find common subexpressions.
+    common: HashMap<(String, Vec<usize>), Vec<RefValue<T>>>,
+}
+
+// The result of simplifying an operation.
+enum Simplified<T: Float> {
+    // The operation can be replaced by an existing node.
+    Node(RefValue<T>),
+    // The operation, possibly a cheaper one, still has to be applied.
+    Apply(Op<T>, Vec<RefValue<T>>),
+}
+
+#[allow(clippy::mutable_key_type)]
+impl<T: Float> Pass<T> {
+    fn optimize_node(&mut self, node: &RefValue<T>) -> RefValue<T> {
+        let (op, children, data, hooked) = {
+            let n = node.get().borrow();
+            (n.op().cloned(), n.children.clone(), n.data, !n.hooks().is_empty())
+        };
+
+        let Some(op) = op else {
+            if self.variables.contains(node) {
+                return node.clone();
+            }
+            return self.constant(data, || node.clone());
+        };
+
+        let children: Vec<RefValue<T>> =
+            children.iter().map(|c| self.optimized[c].clone()).collect();
+        if children.iter().all(|c| self.is_constant(c)) {
+            let inputs: Vec<T> = children.iter().map(|c| c.get().borrow().data).collect();
+            let value = op.forward(&inputs);
+            return self.constant(value, || Value::leaf(value));
+        }
+
+        // Hooks change the gradient flowing through the node, so it can't be replaced by
+        // another node, and other nodes can't be replaced by it.
+        if hooked {
+            return Self::rebuild(node, op, children);
+        }
+
+        match self.simplify(op, children) {
+            Simplified::Node(node) => node,
+            Simplified::Apply(op, children) => self.common_node(node, op, children),
+        }
+    }
+
+    fn is_constant(&self, node: &RefValue<T>) -> bool {
+        node.get().borrow().op().is_none() && !self.variables.contains(node)
+    }
+
+    // Whether `node` is a constant equal to `value`.
+    fn is_constant_value(&self, node: &RefValue<T>, value: T) -> bool {
+        self.is_constant(node) && node.get().borrow().data == value
+    }
+
+    // Returns the leaf for the constant `value`, creating it with `leaf` the first time.
+    fn constant(&mut self, value: T, leaf: impl FnOnce() -> RefValue<T>) -> RefValue<T> {
+        let bits = value.to_f64().map_or(u64::MAX, f64::to_bits);
+        self.constants.entry(bits).or_insert_with(leaf).clone()
+    }
+
+    fn simplify(&self, op: Op<T>, mut children: Vec<RefValue<T>>) -> Simplified<T> {
+        let (zero, one) = (T::zero(), T::one());
+        let is = |index: usize, value: T| self.is_constant_value(&children[index], value);
+        let child = |index: usize| Simplified::Node(children[index].clone());
+        let neg = |index: usize| Simplified::Apply(Op::Neg, vec![children[index].clone()]);
+
+        match op {
+            Op::Add if is(1, zero) => child(0),
+            Op::Add if is(0, zero) => child(1),
+            Op::Sub if is(1, zero) => child(0),
+            Op::Sub if is(0, zero) => neg(1),
+            Op::Mul if is(1, one) => child(0),
+            Op::Mul if is(0, one) => child(1),
+            Op::Mul if is(1, -one) => neg(0),
+            Op::Mul if is(0, -one) => neg(1),
+            Op::Div if is(1, one) => child(0),
+            Op::Pow(n) if n == one => child(0),
+            Op::Neg
+                if children[0].get().borrow().op() == Some(&Op::Neg)
+                    && children[0].get().borrow().hooks().is_empty() =>
+            {
+                Simplified::Node(children[0].get().borrow().children[0].clone())
+            }
+            Op::Sum | Op::Prod => {
+                let identity = if op == Op::Sum { zero } else { one };
+                children.retain(|c| !self.is_constant_value(c, identity));
+                if children.len() == 1 {
+                    Simplified::Node(children.remove(0))
+                } else {
+                    Simplified::Apply(op, children)
+                }
+            }
+            op => Simplified::Apply(op, children),
+        }
+    }
+
+    // Returns an existing node applying `op` to `children`, or creates one replacing `original`.
+    fn common_node(
+        &mut self,
+        original: &RefValue<T>,
+        op: Op<T>,
+        children: Vec<RefValue<T>>,
+    ) -> RefValue<T> {
+        let mut ids: Vec<usize> = children.iter().map(|c| self.id(c)).collect();
+        if matches!(op, Op::Add | Op::Mul | Op::Sum | Op::Prod) {
+            ids.sort_unstable();
+        }
+        let key = (op.name().to_string(), ids);
+
+        let existing = self.common.get(&key).and_then(|nodes| {
+            nodes
+                .iter()
+                .find(|node| node.get().borrow().op() == Some(&op))
+                .cloned()
+        });
+        if let Some(existing) = existing {
+            return existing;
+        }
+
+        let node = Self::rebuild(original, op, children);
+        self.id(&node);
+        self.common.entry(key).or_default().push(node.clone());
+        node
+    }
+
+    // Returns `original` if it already applies `op` to `children`, or else a new node doing so
+    // with the label and the hooks of `original`.
+    fn rebuild(original: &RefValue<T>, op: Op<T>, children: Vec<RefValue<T>>) -> RefValue<T> {
+        let o = original.get().borrow();
+        if o.op() == Some(&op) && o.children == children {
+            return original.clone();
+        }
+
+        let node = Value::apply(op, children);
+        {
+            let mut n = node.get().borrow_mut();
+            if let Some(label) = o.label() {
+                n.set_label(label);
+            }
+            n.set_hooks(o.hooks().clone());
+        }
+        node
+    }
+
+    fn id(&mut self, node: &RefValue<T>) -> usize {
+        let next = self.ids.len();
+        *self.ids.entry(node.clone()).or_insert(next)
+    }
+}
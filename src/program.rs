@@ -0,0 +1,258 @@
+use crate::anomaly::{check_backward, check_forward, is_anomaly_detection_enabled};
+use crate::error::{Error, Result};
+use crate::float::Float;
+use crate::op::Op;
+use crate::value::{Hooks, RefValue, Value};
+use std::collections::{HashMap, HashSet};
+
+/// A computation graph traced once from `RefValue`s and replayed on new data.
+///
+/// Building a graph out of `RefValue`s allocates every node, which adds up when the same
+/// computation is repeated, e.g. once per training iteration. `Program::compile` flattens the
+/// graph of a root into vectors like a `Tape` does, after which `forward` and `back_propagate`
+/// only overwrite the entries of those vectors.
+///
+/// Leaves passed as `inputs` to `compile` become placeholders whose data is given to `forward`.
+/// Every other leaf stays bound to its `RefValue`: its data is read on each `forward`, so
+/// parameter updates are picked up, and `back_propagate` writes its gradient back. Gradient
+/// hooks are those registered on the values when the program is compiled.
+#[derive(Debug)]
+pub struct Program<T: Float = f32> {
+    data: Vec<T>,
+    grad: Vec<T>,
+    // The operation of each node, None for leaves.
+    ops: Vec<Option<Op<T>>>,
+    // The range of `args` holding the children of each node.
+    arg_ranges: Vec<(usize, usize)>,
+    // Children indices of all nodes stored back to back.
+    args: Vec<usize>,
+    // The node of each placeholder, None if the root doesn't depend on it.
+    placeholders: Vec<Option<usize>>,
+    // Leaves read from and written back to their value.
+    bindings: Vec<(usize, RefValue<T>)>,
+    // The gradient hooks of each node, see `RefValue::register_hook`.
+    hooks: Vec<Hooks<T>>,
+    // The label of each node, used in error messages.
+    labels: Vec<Option<String>>,
+    // Scratch space for the data of the children of a node.
+    inputs: Vec<T>,
+}
+
+impl<T: Float> Program<T> {
+    /// Traces the graph of `root`, treating each of `inputs` as a placeholder.
+    ///
+    /// Panics if one of `inputs` isn't a leaf.
+    #[allow(clippy::mutable_key_type)]
+    pub fn compile(root: &RefValue<T>, inputs: &[RefValue<T>]) -> Program<T> {
+        for input in inputs {
+            assert!(
+                input.get().borrow().op().is_none(),
+                "inputs of a program must be leaves"
+            );
+        }
+
+        let mut topo = vec![];
+        let mut visited = HashSet::new();
+        Value::topological_sort(root, &mut topo, &mut visited);
+
+        // Children always come before their parent, so the root is the last node.
+        let indices: HashMap<RefValue<T>, usize> = topo
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.clone(), index))
+            .collect();
+        let placeholders: HashSet<&RefValue<T>> = inputs.iter().collect();
+
+        let mut program = Program {
+            data: Vec::with_capacity(topo.len()),
+            grad: vec![T::zero(); topo.len()],
+            ops: Vec::with_capacity(topo.len()),
+            arg_ranges: Vec::with_capacity(topo.len()),
+            args: vec![],
+            placeholders: inputs.iter().map(|input| indices.get(input).copied()).collect(),
+            bindings: vec![],
+            hooks: Vec::with_capacity(topo.len()),
+            labels: Vec::with_capacity(topo.len()),
+            inputs: vec![],
+        };
+
+        for (index, node) in topo.iter().enumerate() {
+            let n = node.get().borrow();
+            let start = program.args.len();
+            program.args.extend(n.children.iter().map(|child| indices[child]));
+            program.arg_ranges.push((start, program.args.len()));
+            program.data.push(n.data);
+            program.ops.push(n.op().cloned());
+            program.hooks.push(n.hooks().clone());
+            program.labels.push(n.label().map(String::from));
+
+            if n.op().is_none() && !placeholders.contains(node) {
+                program.bindings.push((index, node.clone()));
+            }
+        }
+
+        program
+    }
+
+    /// Recomputes every node with the placeholders set to `inputs` and returns the root.
+    ///
+    /// Panics on the errors `try_forward` returns.
+    pub fn forward(&mut self, inputs: &[T]) -> T {
+        self.forward_with(inputs, true).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `forward` but returns an error if `inputs` doesn't have one entry per placeholder
+    /// or a bound leaf is borrowed mutably.
+    pub fn try_forward(&mut self, inputs: &[T]) -> Result<T> {
+        self.forward_with(inputs, false)
+    }
+
+    // `wait` is set by the panicking functions, see `RefValue::try_borrow`.
+    pub(crate) fn forward_with(&mut self, inputs: &[T], wait: bool) -> Result<T> {
+        if inputs.len() != self.placeholders.len() {
+            return Err(Error::ShapeMismatch {
+                expected: self.placeholders.len(),
+                actual: inputs.len(),
+            });
+        }
+
+        for (placeholder, &x) in self.placeholders.iter().zip(inputs) {
+            if let Some(index) = *placeholder {
+                self.data[index] = x;
+            }
+        }
+        for (index, value) in &self.bindings {
+            self.data[*index] = value.try_borrow(wait)?.data;
+        }
+
+        for index in 0..self.data.len() {
+            let Some(op) = &self.ops[index] else {
+                continue;
+            };
+
+            let (start, end) = self.arg_ranges[index];
+            self.inputs.clear();
+            self.inputs
+                .extend(self.args[start..end].iter().map(|&arg| self.data[arg]));
+            self.data[index] = op.forward(&self.inputs);
+            if is_anomaly_detection_enabled() {
+                check_forward(op, self.data[index], || format!("{:?}", self.inputs))?;
+            }
+        }
+
+        Ok(self.output())
+    }
+
+    /// Computes gradients from the data of the last `forward`, setting the `grad` of every
+    /// bound leaf like `Value::back_propagate` would.
+    ///
+    /// Panics on the errors `try_back_propagate` returns.
+    pub fn back_propagate(&mut self) {
+        self.back_propagate_with(true).unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    /// Like `back_propagate` but returns an error if a custom operation returns the wrong
+    /// number of local gradients or a bound leaf is borrowed.
+    pub fn try_back_propagate(&mut self) -> Result<()> {
+        self.back_propagate_with(false)
+    }
+
+    // See `forward_with`.
+    pub(crate) fn back_propagate_with(&mut self, wait: bool) -> Result<()> {
+        // Resetting grad
+        self.grad.fill(T::zero());
+
+        // Set the gradient of the output to 1.0
+        let root = self.grad.len() - 1;
+        self.grad[root] = T::one();
+
+        // Backpropagate, children always come before their parent.
+        for index in (0..=root).rev() {
+            if !self.hooks[index].is_empty() {
+                self.grad[index] = self.hooks[index].apply(self.grad[index]);
+            }
+
+            let Some(op) = &self.ops[index] else {
+                continue;
+            };
+
+            let (start, end) = self.arg_ranges[index];
+            let args = &self.args[start..end];
+            self.inputs.clear();
+            self.inputs.extend(args.iter().map(|&arg| self.data[arg]));
+
+            let grad = self.grad[index];
+            let local_grads = op.local_grads(&self.inputs, self.data[index]);
+            if local_grads.len() != args.len() {
+                return Err(Error::LocalGrads {
+                    op: op.name().to_string(),
+                    children: args.len(),
+                    grads: local_grads.len(),
+                });
+            }
+            if is_anomaly_detection_enabled() {
+                check_backward(
+                    op,
+                    grad,
+                    &local_grads,
+                    || format!("{:?}", self.inputs),
+                    || self.path_from_root(index),
+                )?;
+            }
+            for (&arg, local_grad) in args.iter().zip(local_grads) {
+                self.grad[arg] = self.grad[arg] + local_grad * grad;
+            }
+        }
+
+        for (index, value) in &self.bindings {
+            value.try_borrow_mut(wait)?.grad = self.grad[*index];
+        }
+        Ok(())
+    }
+
+    // Names the nodes leading from the root, the last node, to the node at `index`.
+    fn path_from_root(&self, index: usize) -> Vec<String> {
+        // Any parent will do as parents come later, so following them ends at the root.
+        let mut parents = vec![None; self.len()];
+        for (parent, &(start, end)) in self.arg_ranges.iter().enumerate() {
+            for &arg in &self.args[start..end] {
+                parents[arg].get_or_insert(parent);
+            }
+        }
+
+        let mut path = vec![];
+        let mut current = Some(index);
+        while let Some(index) = current {
+            path.push(match (&self.labels[index], &self.ops[index]) {
+                (Some(label), _) => label.clone(),
+                (None, Some(op)) => op.name().to_string(),
+                (None, None) => String::from("leaf"),
+            });
+            current = parents[index];
+        }
+        path.reverse();
+        path
+    }
+
+    /// The data of the root as of the last `forward`.
+    pub fn output(&self) -> T {
+        self.data[self.data.len() - 1]
+    }
+
+    /// The gradient of the root with respect to each placeholder as of the last `back_propagate`.
+    pub fn input_grads(&self) -> Vec<T> {
+        self.placeholders
+            .iter()
+            .map(|placeholder| placeholder.map_or(T::zero(), |index| self.grad[index]))
+            .collect()
+    }
+
+    /// Number of nodes in the program.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
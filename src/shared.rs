@@ -0,0 +1,77 @@
+//! Pointer and interior mutability types used by the graph.
+//!
+//! By default values are single threaded and use `Rc<RefCell<_>>`. With the `sync`
+//! feature enabled they use `Arc` and a `RwLock` instead, which makes `RefValue` and the
+//! `neuron` types `Send` and `Sync`. Both cells expose the same `borrow`/`borrow_mut`
+//! methods, and `try_` variants of them, so code using `RefValue::get` is the same in
+//! either mode.
+
+#[cfg(not(feature = "sync"))]
+mod imp {
+    pub type Shared<T> = std::rc::Rc<T>;
+
+    pub type SharedCell<T> = std::cell::RefCell<T>;
+
+    /// Bound required of trait objects stored in the graph, `Send + Sync` with the `sync` feature.
+    pub trait MaybeSendSync {}
+
+    impl<T: ?Sized> MaybeSendSync for T {}
+}
+
+#[cfg(feature = "sync")]
+mod imp {
+    use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    pub type Shared<T> = std::sync::Arc<T>;
+
+    /// A `RwLock` with the borrowing interface of `RefCell`.
+    #[derive(Debug, Default)]
+    pub struct SharedCell<T>(RwLock<T>);
+
+    impl<T> SharedCell<T> {
+        pub fn new(value: T) -> SharedCell<T> {
+            SharedCell(RwLock::new(value))
+        }
+
+        pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+            // A panic in another thread can't leave a value half updated, so poisoning is ignored.
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Like `borrow` but fails instead of blocking while the value is being written.
+        pub fn try_borrow(
+            &self,
+        ) -> Result<RwLockReadGuard<'_, T>, TryLockError<RwLockReadGuard<'_, T>>> {
+            match self.0.try_read() {
+                Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+                result => result,
+            }
+        }
+
+        /// Like `borrow_mut` but fails instead of blocking while the value is being read or
+        /// written.
+        pub fn try_borrow_mut(
+            &self,
+        ) -> Result<RwLockWriteGuard<'_, T>, TryLockError<RwLockWriteGuard<'_, T>>> {
+            match self.0.try_write() {
+                Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+                result => result,
+            }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    /// Bound required of trait objects stored in the graph, `Send + Sync` with the `sync` feature.
+    pub trait MaybeSendSync: Send + Sync {}
+
+    impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
+}
+
+pub use imp::*;
@@ -0,0 +1,395 @@
+use crate::error::Error;
+use crate::float::Float;
+use crate::op::{CustomOp, Op};
+use crate::shared::Shared;
+use std::cell::RefCell;
+use std::fmt;
+use std::ops;
+
+/// An arena that records a computation graph as a flat list of nodes.
+///
+/// Unlike `RefValue`, where every node is its own heap allocation, all nodes of a
+/// `Tape` live in one contiguous `Vec` and refer to their children by index. As a
+/// child is always recorded before the node using it, the order of the tape is
+/// already a topological order and back propagation is a single reverse scan.
+#[derive(Debug)]
+pub struct Tape<T: Float = f32> {
+    nodes: RefCell<Nodes<T>>,
+}
+
+#[derive(Debug)]
+struct Nodes<T: Float> {
+    data: Vec<T>,
+    grad: Vec<T>,
+    // The operation of each node, None for leaves.
+    ops: Vec<Option<Op<T>>>,
+    // The range of `args` holding the children of each node.
+    arg_ranges: Vec<(usize, usize)>,
+    // Children indices of all nodes stored back to back.
+    args: Vec<usize>,
+    // The generation each node was recorded in, see `generation`.
+    generations: Vec<u64>,
+    // Incremented by every `truncate` that forgets nodes, so that a `Var` of a forgotten node
+    // doesn't silently refer to the node recorded at the same index afterwards.
+    generation: u64,
+}
+
+impl<T: Float> Nodes<T> {
+    // Panics unless `var` still refers to the node it was created for.
+    fn check(&self, var: &Var<'_, T>) {
+        assert!(
+            self.generations.get(var.index) == Some(&var.generation),
+            "Var refers to a node forgotten by Tape::truncate"
+        );
+    }
+}
+
+/// A handle to a node recorded on a `Tape`.
+#[derive(Clone, Copy)]
+pub struct Var<'t, T: Float = f32> {
+    tape: &'t Tape<T>,
+    index: usize,
+    generation: u64,
+}
+
+impl Tape {
+    pub fn new() -> Tape {
+        Tape::empty()
+    }
+}
+
+impl<T: Float> Tape<T> {
+    /// Creates a tape for any float type, `new` is the `f32` shorthand.
+    pub fn empty() -> Tape<T> {
+        Tape {
+            nodes: RefCell::new(Nodes {
+                data: vec![],
+                grad: vec![],
+                ops: vec![],
+                arg_ranges: vec![],
+                args: vec![],
+                generations: vec![],
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Records a leaf value.
+    pub fn var(&self, data: T) -> Var<'_, T> {
+        let mut nodes = self.nodes.borrow_mut();
+        let start = nodes.args.len();
+        nodes.data.push(data);
+        nodes.grad.push(T::zero());
+        nodes.ops.push(None);
+        nodes.arg_ranges.push((start, start));
+        let generation = nodes.generation;
+        nodes.generations.push(generation);
+
+        Var {
+            tape: self,
+            index: nodes.data.len() - 1,
+            generation,
+        }
+    }
+
+    /// Records the result of applying `op` to `args`.
+    ///
+    /// Panics if `op` can't take that many arguments, see `Op::check_arity`.
+    pub fn apply<'t>(&'t self, op: Op<T>, args: &[Var<'t, T>]) -> Var<'t, T> {
+        if let Err(err) = op.check_arity(args.len()) {
+            panic!("{}", err);
+        }
+        let mut nodes = self.nodes.borrow_mut();
+        let inputs: Vec<T> = args
+            .iter()
+            .map(|arg| {
+                assert!(
+                    std::ptr::eq(arg.tape, self),
+                    "Var belongs to a different tape"
+                );
+                nodes.check(arg);
+                nodes.data[arg.index]
+            })
+            .collect();
+
+        let start = nodes.args.len();
+        nodes.args.extend(args.iter().map(|arg| arg.index));
+        let end = nodes.args.len();
+
+        nodes.data.push(op.forward(&inputs));
+        nodes.grad.push(T::zero());
+        nodes.ops.push(Some(op));
+        nodes.arg_ranges.push((start, end));
+        let generation = nodes.generation;
+        nodes.generations.push(generation);
+
+        Var {
+            tape: self,
+            index: nodes.data.len() - 1,
+            generation,
+        }
+    }
+
+    /// Applies a user-defined operation to `args`.
+    pub fn custom<'t, O: CustomOp<T> + 'static>(
+        &'t self,
+        op: O,
+        args: &[Var<'t, T>],
+    ) -> Var<'t, T> {
+        self.apply(Op::Custom(Shared::new(op)), args)
+    }
+
+    /// Number of nodes recorded so far.
+    pub fn len(&self) -> usize {
+        self.nodes.borrow().data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every node recorded after the first `len` ones.
+    ///
+    /// Recording the parameters of a model first and truncating back to them after
+    /// each training step lets the same tape be reused without growing. Using a `Var` of
+    /// a forgotten node afterwards panics, even once another node is recorded in its place.
+    pub fn truncate(&self, len: usize) {
+        let mut nodes = self.nodes.borrow_mut();
+        if len >= nodes.data.len() {
+            return;
+        }
+
+        let args_len = nodes.arg_ranges[len].0;
+        nodes.data.truncate(len);
+        nodes.grad.truncate(len);
+        nodes.ops.truncate(len);
+        nodes.arg_ranges.truncate(len);
+        nodes.args.truncate(args_len);
+        nodes.generations.truncate(len);
+        nodes.generation += 1;
+    }
+
+    /// Computes the gradient of `root` with respect to every node recorded before it.
+    ///
+    /// Panics if a custom operation returns a number of local gradients different from its
+    /// number of arguments, like `Value::back_propagate`.
+    pub fn back_propagate(&self, root: Var<'_, T>) {
+        assert!(std::ptr::eq(root.tape, self), "Var belongs to a different tape");
+
+        let mut guard = self.nodes.borrow_mut();
+        let nodes = &mut *guard;
+        nodes.check(&root);
+
+        // Resetting grad
+        nodes.grad[..=root.index].fill(T::zero());
+
+        // Set the gradient of the output to 1.0
+        nodes.grad[root.index] = T::one();
+
+        // Backpropagate, children always come before their parent on the tape.
+        let mut inputs = Vec::new();
+        for index in (0..=root.index).rev() {
+            let Some(op) = &nodes.ops[index] else {
+                continue;
+            };
+
+            let (start, end) = nodes.arg_ranges[index];
+            let args = &nodes.args[start..end];
+            inputs.clear();
+            inputs.extend(args.iter().map(|&arg| nodes.data[arg]));
+
+            let grad = nodes.grad[index];
+            let local_grads = op.local_grads(&inputs, nodes.data[index]);
+            if local_grads.len() != args.len() {
+                let err = Error::LocalGrads {
+                    op: op.name().to_string(),
+                    children: args.len(),
+                    grads: local_grads.len(),
+                };
+                panic!("{}", err);
+            }
+            for (&arg, local_grad) in args.iter().zip(local_grads) {
+                nodes.grad[arg] = nodes.grad[arg] + local_grad * grad;
+            }
+        }
+    }
+}
+
+impl<T: Float> Default for Tape<T> {
+    fn default() -> Self {
+        Tape::empty()
+    }
+}
+
+impl<'t, T: Float> Var<'t, T> {
+    pub fn data(&self) -> T {
+        let nodes = self.tape.nodes.borrow();
+        nodes.check(self);
+        nodes.data[self.index]
+    }
+
+    pub fn set_data(&self, data: T) {
+        let mut nodes = self.tape.nodes.borrow_mut();
+        nodes.check(self);
+        nodes.data[self.index] = data;
+    }
+
+    pub fn grad(&self) -> T {
+        let nodes = self.tape.nodes.borrow();
+        nodes.check(self);
+        nodes.grad[self.index]
+    }
+
+    /// The operation that produced this node, or `None` for a leaf.
+    pub fn op(&self) -> Option<Op<T>> {
+        let nodes = self.tape.nodes.borrow();
+        nodes.check(self);
+        nodes.ops[self.index].clone()
+    }
+
+    /// Position of the node on its tape.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn relu(self) -> Var<'t, T> {
+        self.tape.apply(Op::Relu, &[self])
+    }
+
+    pub fn tanh(self) -> Var<'t, T> {
+        self.tape.apply(Op::Tanh, &[self])
+    }
+
+    pub fn exp(self) -> Var<'t, T> {
+        self.tape.apply(Op::Exp, &[self])
+    }
+
+    pub fn pow(self, other: T) -> Var<'t, T> {
+        self.tape.apply(Op::Pow(other), &[self])
+    }
+
+    /// Takes a gradient descent step using the gradient of the last back propagation.
+    pub fn backward(&self, learning_rate: T) {
+        let mut nodes = self.tape.nodes.borrow_mut();
+        nodes.check(self);
+        let grad = nodes.grad[self.index];
+        nodes.data[self.index] = nodes.data[self.index] - learning_rate * grad;
+    }
+}
+
+impl<T: Float> fmt::Debug for Var<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Var")
+            .field("index", &self.index)
+            .field("data", &self.data())
+            .field("grad", &self.grad())
+            .finish()
+    }
+}
+
+impl<T: Float> fmt::Display for Var<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nodes = self.tape.nodes.borrow();
+        nodes.check(self);
+        let (start, end) = nodes.arg_ranges[self.index];
+        write!(
+            f,
+            "Var(data={:.4}, op={}, grad={:.4}, children={})",
+            nodes.data[self.index],
+            nodes.ops[self.index].as_ref().map_or("?", Op::name),
+            nodes.grad[self.index],
+            end - start
+        )
+    }
+}
+
+impl<'t, T: Float> ops::Neg for Var<'t, T> {
+    type Output = Var<'t, T>;
+
+    fn neg(self) -> Self::Output {
+        self.tape.apply(Op::Neg, &[self])
+    }
+}
+
+// Implements a binary operator between vars as well as with scalars on the right hand side
+// and integers on either side, scalars are recorded on the tape as leaves.
+macro_rules! impl_var_op {
+    ($trait:ident, $method:ident, $build:expr) => {
+        impl<'t, T: Float> ops::$trait<Var<'t, T>> for Var<'t, T> {
+            type Output = Var<'t, T>;
+
+            fn $method(self, rhs: Var<'t, T>) -> Self::Output {
+                $build(self, rhs)
+            }
+        }
+
+        impl<'t, T: Float> ops::$trait<T> for Var<'t, T> {
+            type Output = Var<'t, T>;
+
+            fn $method(self, rhs: T) -> Self::Output {
+                $build(self, self.tape.var(rhs))
+            }
+        }
+
+        impl<'t, T: Float> ops::$trait<i32> for Var<'t, T> {
+            type Output = Var<'t, T>;
+
+            fn $method(self, rhs: i32) -> Self::Output {
+                $build(self, self.tape.var(T::from_f64(rhs as f64)))
+            }
+        }
+
+        impl<'t, T: Float> ops::$trait<Var<'t, T>> for i32 {
+            type Output = Var<'t, T>;
+
+            fn $method(self, rhs: Var<'t, T>) -> Self::Output {
+                $build(rhs.tape.var(T::from_f64(self as f64)), rhs)
+            }
+        }
+    };
+}
+
+// Implements a binary operator with a float scalar on the left hand side, which can't be
+// done generically over `T` as the scalar type itself is foreign.
+macro_rules! impl_var_scalar_op {
+    ($t:ty, $trait:ident, $method:ident, $build:expr) => {
+        impl<'t> ops::$trait<Var<'t, $t>> for $t {
+            type Output = Var<'t, $t>;
+
+            fn $method(self, rhs: Var<'t, $t>) -> Self::Output {
+                $build(rhs.tape.var(self), rhs)
+            }
+        }
+    };
+}
+
+fn add<'t, T: Float>(lhs: Var<'t, T>, rhs: Var<'t, T>) -> Var<'t, T> {
+    lhs.tape.apply(Op::Add, &[lhs, rhs])
+}
+
+fn mul<'t, T: Float>(lhs: Var<'t, T>, rhs: Var<'t, T>) -> Var<'t, T> {
+    lhs.tape.apply(Op::Mul, &[lhs, rhs])
+}
+
+fn sub<'t, T: Float>(lhs: Var<'t, T>, rhs: Var<'t, T>) -> Var<'t, T> {
+    lhs.tape.apply(Op::Sub, &[lhs, rhs])
+}
+
+fn div<'t, T: Float>(lhs: Var<'t, T>, rhs: Var<'t, T>) -> Var<'t, T> {
+    lhs.tape.apply(Op::Div, &[lhs, rhs])
+}
+
+impl_var_op!(Add, add, add);
+impl_var_op!(Sub, sub, sub);
+impl_var_op!(Mul, mul, mul);
+impl_var_op!(Div, div, div);
+
+impl_var_scalar_op!(f32, Add, add, add);
+impl_var_scalar_op!(f32, Sub, sub, sub);
+impl_var_scalar_op!(f32, Mul, mul, mul);
+impl_var_scalar_op!(f32, Div, div, div);
+
+impl_var_scalar_op!(f64, Add, add, add);
+impl_var_scalar_op!(f64, Sub, sub, sub);
+impl_var_scalar_op!(f64, Mul, mul, mul);
+impl_var_scalar_op!(f64, Div, div, div);
@@ -1,428 +1,1000 @@
-use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::collections::HashSet;
+use crate::anomaly::{check_backward, check_forward, is_anomaly_detection_enabled};
+use crate::error::{Error, Result};
+use crate::float::Float;
+use crate::grad_mode::is_grad_enabled;
+use crate::op::{CustomOp, Op};
+use crate::shared::{MaybeSendSync, Shared, SharedCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::rc::Rc;
-use std::ops;
+use std::iter;
+use std::ops::{self, Deref, DerefMut};
 
 #[derive(Debug, Clone)]
-pub struct Value {
+pub struct Value<T: Float = f32> {
     // The data associated with the value.
-    pub data: f32,
+    pub data: T,
 
     // The children if its a non-leaf value that lead to its computation.
     // This helps maintain a computation graph.
-    pub children: Vec<RefValue>,
-
-    // This contains other value that was used alongside the operation to compute but not part of computation graph.
-    non_chained_deps: Option<[f32; 1]>,
+    pub children: Vec<RefValue<T>>,
 
     // The gradient calculated.
-    pub grad: f32,
+    pub grad: T,
 
     // Defines the operation associated with the value.
-    // For example if its + then it means the value was output of addition of two values.
+    // For example if its Op::Add then it means the value was output of addition of two values.
     // If None then it's a leaf value.
-    op: Option<&'static str>,
+    op: Option<Op<T>>,
+
+    // Optional name shown when exporting the graph.
+    label: Option<String>,
+
+    // Functions called with the gradient of the value during back propagation.
+    hooks: Hooks<T>,
+}
+
+/// A function called with the gradient flowing into a value, see `RefValue::register_hook`.
+///
+/// Returning `Some` replaces the gradient, returning `None` leaves it unchanged.
+/// With the `sync` feature the function must also be `Send + Sync`.
+pub trait GradHook<T: Float>: Fn(T) -> Option<T> + MaybeSendSync {}
+
+impl<T: Float, F: Fn(T) -> Option<T> + MaybeSendSync> GradHook<T> for F {}
+
+// The gradient hooks of a value, in the order they were registered.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks<T: Float>(Vec<Shared<dyn GradHook<T>>>);
+
+impl<T: Float> Hooks<T> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Passes `grad` through every hook, each one seeing the result of the previous one.
+    pub(crate) fn apply(&self, grad: T) -> T {
+        self.0.iter().fold(grad, |grad, hook| hook(grad).unwrap_or(grad))
+    }
+}
+
+impl<T: Float> fmt::Debug for Hooks<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hooks({})", self.0.len())
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct RefValue(Rc<RefCell<Value>>);
+pub struct RefValue<T: Float = f32>(Shared<SharedCell<Value<T>>>);
 
 impl Value {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(data: f32) -> RefValue {
-        RefValue(Rc::new(RefCell::new(Value {
-            data: data,
+        Value::leaf(data)
+    }
+}
+
+impl<T: Float> Value<T> {
+    /// Creates a leaf holding `data` for any float type, `new` is the `f32` shorthand.
+    pub fn leaf(data: T) -> RefValue<T> {
+        RefValue(Shared::new(SharedCell::new(Value {
+            data,
             children: vec![],
-            non_chained_deps: None,
-            grad: 0.0,
+            grad: T::zero(),
             op: None,
+            label: None,
+            hooks: Hooks::default(),
         })))
     }
 
-    pub fn back_propagate(val: &RefValue) -> () {
+    // Creates a non-leaf value by applying `op` to the data of `children`.
+    // When grad is disabled only the data is computed and a leaf is returned.
+    fn from_op(op: Op<T>, children: Vec<RefValue<T>>) -> RefValue<T> {
+        let inputs: Vec<T> = children.iter().map(|c| c.get().borrow().data).collect();
+        Self::from_inputs(op, children, &inputs).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    // Like `from_op`, but fails instead of panicking when one of `children` is borrowed or when
+    // anomaly detection finds a non-finite output.
+    fn try_from_op(op: Op<T>, children: Vec<RefValue<T>>) -> Result<RefValue<T>> {
+        let inputs = children
+            .iter()
+            .map(|c| Ok(c.try_borrow(false)?.data))
+            .collect::<Result<Vec<T>>>()?;
+        Self::from_inputs(op, children, &inputs)
+    }
+
+    // Builds the node of `op` from the data of its children, read by the caller.
+    fn from_inputs(op: Op<T>, children: Vec<RefValue<T>>, inputs: &[T]) -> Result<RefValue<T>> {
+        let data = op.forward(inputs);
+        if is_anomaly_detection_enabled() {
+            check_forward(&op, data, || describe_values(&children))?;
+        }
+        if !is_grad_enabled() {
+            return Ok(Value::leaf(data));
+        }
+
+        Ok(RefValue(Shared::new(SharedCell::new(Value {
+            data,
+            children,
+            grad: T::zero(),
+            op: Some(op),
+            label: None,
+            hooks: Hooks::default(),
+        }))))
+    }
+
+    /// The operation that produced this value, or `None` for a leaf.
+    pub fn op(&self) -> Option<&Op<T>> {
+        self.op.as_ref()
+    }
+
+    /// The name given to this value with `set_label` or `RefValue::with_label`, if any.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn set_label(&mut self, label: impl Into<String>) {
+        self.label = Some(label.into());
+    }
+
+    pub(crate) fn hooks(&self) -> &Hooks<T> {
+        &self.hooks
+    }
+
+    pub(crate) fn set_hooks(&mut self, hooks: Hooks<T>) {
+        self.hooks = hooks;
+    }
+
+    /// Computes the gradient of `val` with respect to every value of its graph.
+    ///
+    /// Panics on the errors `try_back_propagate` returns.
+    pub fn back_propagate(val: &RefValue<T>) {
+        Self::propagate(std::slice::from_ref(val), &[T::one()], false, true)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    /// Like `back_propagate` but returns an error for a malformed graph or a value that is
+    /// already borrowed, e.g. by a guard held across the call.
+    ///
+    /// The graph is checked before any gradient is changed.
+    pub fn try_back_propagate(val: &RefValue<T>) -> Result<()> {
+        Self::propagate(std::slice::from_ref(val), &[T::one()], false, false)
+    }
+
+    /// Back propagates from all of `roots` in a single pass, starting each root from its
+    /// entry in `seeds` instead of 1.
+    ///
+    /// The `grad` of a leaf becomes the sum over the roots of the seed times the derivative
+    /// of the root, i.e. the vector-Jacobian product of `seeds` with the roots, such as the
+    /// upstream gradient of the outputs of `MultiLayerPerceptron::forward`. A root that is
+    /// also part of the graph of another root gets its seed on top of the gradient flowing
+    /// into it.
+    ///
+    /// Panics on the errors `try_back_propagate_seeded` returns.
+    pub fn back_propagate_seeded(roots: &[RefValue<T>], seeds: &[T]) {
+        Self::propagate(roots, seeds, false, true).unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    /// Like `back_propagate_seeded` but returns an error if there isn't one seed per root,
+    /// see also `try_back_propagate`.
+    pub fn try_back_propagate_seeded(roots: &[RefValue<T>], seeds: &[T]) -> Result<()> {
+        Self::propagate(roots, seeds, false, false)
+    }
+
+    /// Back propagates like `back_propagate` but adds to the `grad` of leaves instead of
+    /// replacing it, so gradients of several losses or micro-batches can be summed before
+    /// an update. Interior nodes still start from 0 as their gradients belong to this pass.
+    ///
+    /// Use `NetworkParameters::zero_grad` to clear the accumulated gradients.
+    pub fn back_propagate_accumulate(val: &RefValue<T>) {
+        Self::propagate(std::slice::from_ref(val), &[T::one()], true, true)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    // `wait` is set by the panicking functions, see `RefValue::try_borrow`.
+    #[allow(clippy::mutable_key_type)]
+    fn propagate(roots: &[RefValue<T>], seeds: &[T], accumulate: bool, wait: bool) -> Result<()> {
+        if roots.len() != seeds.len() {
+            return Err(Error::ShapeMismatch {
+                expected: roots.len(),
+                actual: seeds.len(),
+            });
+        }
+
+        // Sorting every root into the same `topo` keeps children before all of their parents.
         let mut topo = vec![];
         let mut visited = HashSet::new();
-    
-        Self::topological_sort(&val, &mut topo, &mut visited);
-    
-        // Resetting grad
+        for root in roots {
+            Self::try_topological_sort(root, &mut topo, &mut visited, wait)?;
+        }
         for node in &topo {
-            node.get().borrow_mut().grad = 0.0;
+            let n = node.try_borrow(wait)?;
+            if let Some(op) = &n.op {
+                op.check_arity(n.children.len())?;
+            }
         }
-        
-        // Set the gradient of the output to 1.0
-        val.get().borrow_mut().grad = 1.0;
-    
+
+        // Resetting grad, the gradients kept by leaves are added back once hooks have seen
+        // the gradient of this pass.
+        let mut kept = vec![];
+        for node in &topo {
+            let mut n = node.try_borrow_mut(wait)?;
+            if accumulate && n.op.is_none() && n.grad != T::zero() {
+                kept.push((node.clone(), n.grad));
+            }
+            n.grad = T::zero();
+        }
+
+        // Set the gradient of the outputs to their seed, 1.0 for a single root
+        for (root, &seed) in roots.iter().zip(seeds) {
+            let mut r = root.try_borrow_mut(wait)?;
+            r.grad = r.grad + seed;
+        }
+
         // Backpropagate
         for node in topo.iter().rev() {
-            let (grad, op) = {
-                let n = node.get().borrow();
-                (n.grad, n.op)
+            Self::run_hooks(node, wait)?;
+
+            let n = node.try_borrow(wait)?;
+            let Some(op) = &n.op else {
+                continue;
             };
-    
-            let mut children_data = Vec::new();
-            for child in &node.get().borrow().children {
-                children_data.push(child.get().borrow().data);
+
+            let children_data = n
+                .children
+                .iter()
+                .map(|c| Ok(c.try_borrow(wait)?.data))
+                .collect::<Result<Vec<T>>>()?;
+            let local_grads = op.local_grads(&children_data, n.data);
+            if local_grads.len() != n.children.len() {
+                return Err(Error::LocalGrads {
+                    op: op.name().to_string(),
+                    children: n.children.len(),
+                    grads: local_grads.len(),
+                });
             }
-    
-            for (i, child) in node.get().borrow().children.iter().enumerate() {
-                let child_grad;
-                match op {
-                    Some("+") => {
-                        child_grad = 1.0 * grad;
-                    }
-                    Some("*") => {
-                        let other_child_data = if i == 0 { children_data[1] } else { children_data[0] };
-                        child_grad = grad * other_child_data;
-                    }
-                    Some("tanh") => {
-                        child_grad = (1.0 - node.get().borrow().data.powi(2)) * grad;
-                    }
-                    Some("exp") => {
-                        child_grad = node.get().borrow().data * grad;
-                    }
-                    Some("pow") => {
-                        let n = node.get().borrow().non_chained_deps.unwrap()[0];
-                        child_grad = n * child.get().borrow().data.powf(n - 1.0) * grad;
-                    }
-                    Some("relu") => {
-                        child_grad = if child.get().borrow().data >= 0.0 { grad } else { 0.0 };
-                    }
-                    _ => { unreachable!() }
-                }
-                child.get().borrow_mut().grad += child_grad;
+            if is_anomaly_detection_enabled() {
+                check_backward(
+                    op,
+                    n.grad,
+                    &local_grads,
+                    || describe_values(&n.children),
+                    || Self::path_from_root(&topo, node),
+                )?;
+            }
+
+            for (child, local_grad) in n.children.iter().zip(local_grads) {
+                let mut child = child.try_borrow_mut(wait)?;
+                child.grad = child.grad + local_grad * n.grad;
             }
         }
+
+        for (node, grad) in kept {
+            let mut n = node.try_borrow_mut(wait)?;
+            n.grad = n.grad + grad;
+        }
+        Ok(())
     }
 
-    pub fn topological_sort(
-        node: &RefValue,
-        topo: &mut Vec<RefValue>,
-        visited: &mut HashSet<RefValue>,
-    ) {
-        if visited.contains(node) {
-            return;
+    // Names the nodes leading from a root of `topo`, i.e. a node without parents, to `node`.
+    #[allow(clippy::mutable_key_type)]
+    fn path_from_root(topo: &[RefValue<T>], node: &RefValue<T>) -> Vec<String> {
+        // Any parent will do as parents come later in `topo`, so following them ends at a root.
+        let mut parents = HashMap::new();
+        for parent in topo {
+            for child in parent.get().borrow().children.iter() {
+                parents.entry(child.clone()).or_insert_with(|| parent.clone());
+            }
         }
 
-        visited.insert(node.clone());
-        for child in node.get().borrow().children.iter() {
-            Self::topological_sort(child, topo, visited);
+        let mut path = vec![node.get().borrow().name()];
+        let mut current = node;
+        while let Some(parent) = parents.get(current) {
+            path.push(parent.get().borrow().name());
+            current = parent;
         }
+        path.reverse();
+        path
+    }
 
-        topo.push(node.clone());
+    // The label of the value, or else the name of its op.
+    fn name(&self) -> String {
+        match (&self.label, &self.op) {
+            (Some(label), _) => label.clone(),
+            (None, Some(op)) => op.name().to_string(),
+            (None, None) => String::from("leaf"),
+        }
     }
 
-    pub fn relu(slf: RefValue) -> RefValue {
-        let x = slf.get().borrow().data;
-        let result = x.max(0.0);
-        RefValue(Rc::new(RefCell::new(Value {
-            data: result,
-            op: Some("relu"),
-            children: vec![slf],
-            non_chained_deps: None,
-            grad: 0.0,
-        })))
+    // Lets the hooks of `node` replace its gradient, which is complete once every node using
+    // it has been visited.
+    fn run_hooks(node: &RefValue<T>, wait: bool) -> Result<()> {
+        // The hooks are called without holding a borrow so that they can look at the value.
+        let (hooks, grad) = {
+            let n = node.try_borrow(wait)?;
+            (n.hooks.clone(), n.grad)
+        };
+        if hooks.is_empty() {
+            return Ok(());
+        }
+
+        let grad = hooks.apply(grad);
+        node.try_borrow_mut(wait)?.grad = grad;
+        Ok(())
     }
 
-    pub fn tanh(slf: RefValue) -> RefValue {
-        let x = slf.get().borrow().data;
-        // As `exp` can return infinity which then later converting to tanh can change it to NaN
-        // to fix this we bound the number between Max and Min of f32
-        let e2x = (2.0 * x).exp().min(f32::MAX).max(f32::MIN);
-        let t = (e2x - 1.0) / (e2x + 1.0);
-        RefValue(Rc::new(RefCell::new(Value {
-            data: t,
-            op: Some("tanh"),
-            children: vec![slf],
-            non_chained_deps: None,
-            grad: 0.0,
-        })))
+    /// Computes the gradient of `val` with respect to each of `wrt` as differentiable values.
+    ///
+    /// Unlike `back_propagate`, the backward pass is itself built out of `RefValue` nodes, so
+    /// the returned gradients can be back propagated again to get second and higher derivatives.
+    /// The `grad` of every node in the graph is also set, as `back_propagate` would.
+    #[allow(clippy::mutable_key_type)]
+    pub fn gradients(val: &RefValue<T>, wrt: &[RefValue<T>]) -> Vec<RefValue<T>> {
+        let mut topo = vec![];
+        let mut visited = HashSet::new();
+
+        Self::topological_sort(val, &mut topo, &mut visited);
+
+        let mut grads: HashMap<RefValue<T>, RefValue<T>> = HashMap::new();
+        grads.insert(val.clone(), Value::leaf(T::one()));
+
+        for node in topo.iter().rev() {
+            let Some(grad) = grads.get(node).cloned() else {
+                node.get().borrow_mut().grad = T::zero();
+                continue;
+            };
+            node.get().borrow_mut().grad = grad.get().borrow().data;
+
+            let (op, children) = {
+                let n = node.get().borrow();
+                (n.op.clone(), n.children.clone())
+            };
+            let Some(op) = op else {
+                continue;
+            };
+
+            let local_grads = op.local_grad_values(&children, node);
+            for (child, local_grad) in children.into_iter().zip(local_grads) {
+                let child_grad = local_grad * grad.clone();
+                let child_grad = match grads.remove(&child) {
+                    Some(acc) => acc + child_grad,
+                    None => child_grad,
+                };
+                grads.insert(child, child_grad);
+            }
+        }
+
+        wrt.iter()
+            .map(|x| grads.get(x).cloned().unwrap_or_else(|| Value::leaf(T::zero())))
+            .collect()
+    }
+
+    /// Appends the nodes of the graph of `node` not in `visited` to `topo`, children first.
+    ///
+    /// Panics if the graph has a cycle.
+    #[allow(clippy::mutable_key_type)]
+    pub fn topological_sort(
+        node: &RefValue<T>,
+        topo: &mut Vec<RefValue<T>>,
+        visited: &mut HashSet<RefValue<T>>,
+    ) {
+        Self::try_topological_sort(node, topo, visited, true)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    fn try_topological_sort(
+        node: &RefValue<T>,
+        topo: &mut Vec<RefValue<T>>,
+        visited: &mut HashSet<RefValue<T>>,
+        wait: bool,
+    ) -> Result<()> {
+        // Walk the graph with an explicit stack so deep graphs don't overflow the call stack.
+        // A node is pushed a second time with `expanded` set so that it is only added to
+        // `topo` once all of its children have been added. The nodes in between are exactly
+        // the ancestors of the node being expanded, so reaching one of them again is a cycle.
+        let mut stack = vec![(node.clone(), false)];
+        let mut ancestors = HashSet::new();
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                ancestors.remove(&node);
+                topo.push(node);
+                continue;
+            }
+            if visited.contains(&node) {
+                continue;
+            }
+
+            visited.insert(node.clone());
+            ancestors.insert(node.clone());
+            stack.push((node.clone(), true));
+            for child in node.try_borrow(wait)?.children.iter().rev() {
+                if ancestors.contains(child) {
+                    return Err(Error::Cycle);
+                }
+                if !visited.contains(child) {
+                    stack.push((child.clone(), false));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn relu(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Relu, vec![slf])
+    }
+
+    pub fn tanh(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Tanh, vec![slf])
     }
 
     #[allow(dead_code)]
-    pub fn exp(slf: RefValue) -> RefValue {
-        let x = slf.get().borrow().data;
-        RefValue(Rc::new(RefCell::new(Value {
-            data: x.exp(),
-            op: Some("exp"),
-            children: vec![slf],
-            non_chained_deps: None,
-            grad: 0.0,
-        })))
+    pub fn exp(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Exp, vec![slf])
     }
 
-    pub fn pow(slf: RefValue, other: f32) -> RefValue {
-        let x = slf.get().borrow().data;
-        RefValue(Rc::new(RefCell::new(Value {
-            data: x.powf(other),
-            op: Some("pow"),
-            children: vec![slf],
-            non_chained_deps: Some([other]),
-            grad: 0.0,
-        })))
+    pub fn pow(slf: RefValue<T>, other: T) -> RefValue<T> {
+        Self::from_op(Op::Pow(other), vec![slf])
     }
 
-    pub fn add(slf: RefValue, rhs: RefValue) -> RefValue {
-        let val = RefValue(Rc::new(RefCell::new(Value {
-            data: slf.get().borrow().data + rhs.get().borrow().data,
-            op: Some("+"),
-            children: vec![],
-            non_chained_deps: None,
-            grad: 0.0,
-        })));
-        val.get().borrow_mut().children.extend(vec![slf, rhs]);
+    /// Raises `slf` to the power of `exponent`, differentiating through both.
+    pub fn pow_value(slf: RefValue<T>, exponent: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::PowValue, vec![slf, exponent])
+    }
 
-        val
+    /// Natural logarithm.
+    pub fn log(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Log, vec![slf])
     }
 
-    pub fn mul(slf: RefValue, rhs: RefValue) -> RefValue {
-        let val = RefValue(Rc::new(RefCell::new(Value {
-            data: slf.get().borrow().data * rhs.get().borrow().data,
-            op: Some("*"),
-            children: vec![],
-            non_chained_deps: None,
-            grad: 0.0,
-        })));
+    pub fn sqrt(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Sqrt, vec![slf])
+    }
+
+    /// Absolute value, its gradient at 0 is 0.
+    pub fn abs(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Abs, vec![slf])
+    }
+
+    pub fn neg(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Neg, vec![slf])
+    }
+
+    pub fn sin(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Sin, vec![slf])
+    }
+
+    pub fn cos(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Cos, vec![slf])
+    }
+
+    pub fn sigmoid(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Sigmoid, vec![slf])
+    }
+
+    /// log(1 + e^x), computed without overflowing for large inputs.
+    pub fn softplus(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Softplus, vec![slf])
+    }
+
+    /// Relu which scales negative inputs by `alpha` instead of zeroing them.
+    pub fn leaky_relu(slf: RefValue<T>, alpha: T) -> RefValue<T> {
+        Self::from_op(Op::LeakyRelu(alpha), vec![slf])
+    }
+
+    /// x for positive inputs and alpha * (e^x - 1) otherwise.
+    pub fn elu(slf: RefValue<T>, alpha: T) -> RefValue<T> {
+        Self::from_op(Op::Elu(alpha), vec![slf])
+    }
+
+    /// Gaussian error linear unit, using the tanh approximation.
+    pub fn gelu(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Gelu, vec![slf])
+    }
+
+    /// x * sigmoid(x), also known as swish.
+    pub fn silu(slf: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Silu, vec![slf])
+    }
+
+    /// Limits `slf` to [min, max], the gradient is 0 outside of that range.
+    pub fn clamp(slf: RefValue<T>, min: T, max: T) -> RefValue<T> {
+        Self::from_op(Op::Clamp(min, max), vec![slf])
+    }
+
+    /// The smaller of the two values, on a tie the gradient goes to `slf`.
+    pub fn min(slf: RefValue<T>, rhs: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Min, vec![slf, rhs])
+    }
+
+    /// The larger of the two values, on a tie the gradient goes to `slf`.
+    pub fn max(slf: RefValue<T>, rhs: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Max, vec![slf, rhs])
+    }
+
+    /// Adds up all of `values` in a single node, 0 if there are none.
+    pub fn sum(values: Vec<RefValue<T>>) -> RefValue<T> {
+        Self::from_op(Op::Sum, values)
+    }
+
+    /// Multiplies all of `values` in a single node, 1 if there are none.
+    pub fn product(values: Vec<RefValue<T>>) -> RefValue<T> {
+        Self::from_op(Op::Prod, values)
+    }
+
+    /// Sum of the pairwise products of `xs` and `ws` in a single node.
+    pub fn dot(xs: &[RefValue<T>], ws: &[RefValue<T>]) -> RefValue<T> {
+        assert_eq!(xs.len(), ws.len(), "dot product of values with different lengths");
+
+        let mut children = xs.to_vec();
+        children.extend_from_slice(ws);
+        Self::from_op(Op::Dot, children)
+    }
+
+    /// log(e^x1 + ... + e^xn) in a single node, computed without overflowing for large inputs.
+    pub fn logsumexp(xs: &[RefValue<T>]) -> RefValue<T> {
+        Self::from_op(Op::LogSumExp, xs.to_vec())
+    }
+
+    /// The logarithm of the softmax of `xs`, i.e. `x - logsumexp(xs)` for each of `xs`.
+    ///
+    /// Each output is a single node with all of `xs` as children, see `softmax`.
+    pub fn log_softmax(xs: &[RefValue<T>]) -> Vec<RefValue<T>> {
+        (0..xs.len())
+            .map(|i| Self::from_op(Op::LogSoftmax(i), xs.to_vec()))
+            .collect()
+    }
+
+    /// Normalizes `xs` into probabilities which are positive and add up to 1.
+    ///
+    /// Each output is a single node computed as `exp(x - logsumexp(xs))`, which never
+    /// exponentiates a positive number so it can't overflow. As every output depends on all
+    /// of `xs`, n inputs add n nodes with n children each.
+    pub fn softmax(xs: &[RefValue<T>]) -> Vec<RefValue<T>> {
+        (0..xs.len())
+            .map(|i| Self::from_op(Op::Softmax(i), xs.to_vec()))
+            .collect()
+    }
 
-        val.get().borrow_mut().children.extend(vec![slf, rhs]);
+    pub fn add(slf: RefValue<T>, rhs: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Add, vec![slf, rhs])
+    }
+
+    pub fn mul(slf: RefValue<T>, rhs: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Mul, vec![slf, rhs])
+    }
+
+    /// Applies any operation to `children`, e.g. one taken from an existing node with `op()`.
+    ///
+    /// Panics if `op` can't take that many children, see `Op::check_arity`.
+    pub fn apply(op: Op<T>, children: Vec<RefValue<T>>) -> RefValue<T> {
+        if let Err(err) = op.check_arity(children.len()) {
+            panic!("{}", err);
+        }
+        Self::from_op(op, children)
+    }
+
+    /// Like `apply`, but returns an error if `op` can't take that many children, if one of them
+    /// is already borrowed or if anomaly detection finds a non-finite output.
+    pub fn try_apply(op: Op<T>, children: Vec<RefValue<T>>) -> Result<RefValue<T>> {
+        op.check_arity(children.len())?;
+        Self::try_from_op(op, children)
+    }
 
-        val
+    /// Applies a user-defined operation to `inputs`.
+    pub fn custom<O: CustomOp<T> + 'static>(op: O, inputs: Vec<RefValue<T>>) -> RefValue<T> {
+        Self::from_op(Op::Custom(Shared::new(op)), inputs)
     }
 
-    pub fn sub(slf: RefValue, rhs: RefValue) -> RefValue {
-        Value::add(slf, Value::mul(rhs, Value::new(-1.0)))
+    pub fn sub(slf: RefValue<T>, rhs: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Sub, vec![slf, rhs])
     }
 
-    pub fn div(slf: RefValue, rhs: RefValue) -> RefValue {
-        Value::mul(slf, Value::pow(rhs, -1.0))
+    pub fn div(slf: RefValue<T>, rhs: RefValue<T>) -> RefValue<T> {
+        Self::from_op(Op::Div, vec![slf, rhs])
     }
 
-    pub fn backward(slf: &RefValue, learning_rate: f32) {
-        let grad = slf.get().borrow().grad;
-        slf.get().borrow_mut().data -= learning_rate * grad;
+    pub fn backward(slf: &RefValue<T>, learning_rate: T) {
+        let mut value = slf.get().borrow_mut();
+        value.data = value.data - learning_rate * value.grad;
     }
 
     #[allow(dead_code)]
-    pub fn print_children(slf: &RefValue) {
+    pub fn print_children(slf: &RefValue<T>) {
         Self::print_children_with_prefix(slf, "");
     }
 
     #[allow(dead_code)]
-    fn print_children_with_prefix(slf: &RefValue, prefix: &str) {
-        let children = &slf.get().borrow().children;
-        println!(
-            "{}{}{}",
-            prefix,
-            slf,
-            if children.len() == 0 { ";" } else { " {" }
-        );
-        for child in children {
-            Self::print_children_with_prefix(child, &(String::from(prefix) + "    "));
-        }
-        if children.len() != 0 {
-            println!("{}}}", prefix);
+    fn print_children_with_prefix(slf: &RefValue<T>, prefix: &str) {
+        // `None` marks the point where the block opened by the node at that depth is closed.
+        let mut stack = vec![(Some(slf.clone()), 0)];
+        while let Some((node, depth)) = stack.pop() {
+            let indent = String::from(prefix) + &"    ".repeat(depth);
+            let Some(node) = node else {
+                println!("{}}}", indent);
+                continue;
+            };
+
+            let children = &node.get().borrow().children;
+            println!(
+                "{}{}{}",
+                indent,
+                node,
+                if children.is_empty() { ";" } else { " {" }
+            );
+            if !children.is_empty() {
+                stack.push((None, depth));
+                for child in children.iter().rev() {
+                    stack.push((Some(child.clone()), depth + 1));
+                }
+            }
         }
     }
 
+    /// Renders the graph of `slf` as a mermaid state diagram.
+    ///
+    /// Each value appears once even if it is used several times, with an edge to every use.
     #[allow(dead_code)]
-    pub fn generate_mermaid_graph(slf: &RefValue) -> String {
-        let mut nodes = Vec::new();
-        let mut edges = Vec::new();
-        Self::generate_mermaid_graph_helper(slf, &mut nodes, &mut edges, 1);
+    pub fn generate_mermaid_graph(slf: &RefValue<T>) -> String {
+        let (nodes, ids) = Self::numbered_nodes(slf);
+
         let mut graph = String::from("stateDiagram-v2\n");
-        graph.push_str(&nodes.join("\n"));
-        graph.push_str("\n");
-        graph.push_str(&edges.join("\n"));
+        for (id, node) in nodes.iter().enumerate() {
+            graph.push_str(&format!("s{} : {}\n", id + 1, node));
+        }
+        for (id, node) in nodes.iter().enumerate() {
+            for child in node.get().borrow().children.iter() {
+                graph.push_str(&format!("s{} --> s{}\n", id + 1, ids[child] + 1));
+            }
+        }
         graph
     }
 
-    #[allow(dead_code)]
-    fn generate_mermaid_graph_helper(
-        slf: &RefValue,
-        nodes: &mut Vec<String>,
-        edges: &mut Vec<String>,
-        id: usize,
-    ) -> usize {
-        let value = slf.get().borrow();
-        nodes.push(format!("s{} : {}", id, value));
-        let mut next_id = id + 1;
-        for child in &value.children {
-            edges.push(format!("s{} --> s{}", id, next_id));
-            next_id = Self::generate_mermaid_graph_helper(child, nodes, edges, next_id);
+    /// Renders the graph of `slf` in the Graphviz DOT format, e.g. for `dot -Tsvg`.
+    ///
+    /// Each value appears once, showing its label, op, data and grad, with edges pointing from
+    /// the inputs of an operation to its output. With `highlight_grad` every value is filled with
+    /// a shade of red proportional to its gradient magnitude relative to the largest in the graph.
+    pub fn generate_dot_graph(slf: &RefValue<T>, highlight_grad: bool) -> String {
+        let (nodes, ids) = Self::numbered_nodes(slf);
+        let max_grad = nodes
+            .iter()
+            .fold(T::zero(), |acc, node| acc.max(node.get().borrow().grad.abs()));
+
+        let mut graph = String::from("digraph {\n    rankdir=LR;\n    node [shape=record];\n");
+        for (id, node) in nodes.iter().enumerate() {
+            let value = node.get().borrow();
+            let mut fields = vec![];
+            if let Some(label) = value.label() {
+                fields.push(escape_dot_record(label));
+            }
+            if let Some(op) = value.op() {
+                fields.push(escape_dot_record(op.name()));
+            }
+            fields.push(format!("data {:.4}", value.data));
+            fields.push(format!("grad {:.4}", value.grad));
+
+            let mut attributes = format!("label=\"{{ {} }}\"", fields.join(" | "));
+            if highlight_grad && max_grad > T::zero() {
+                let shade = (value.grad.abs() / max_grad * T::from_f64(255.0)).round();
+                let alpha = shade.to_u8().unwrap_or(0);
+                attributes.push_str(&format!(", style=filled, fillcolor=\"#ff0000{:02x}\"", alpha));
+            }
+            graph.push_str(&format!("    n{} [{}];\n", id, attributes));
+        }
+        for (id, node) in nodes.iter().enumerate() {
+            for child in node.get().borrow().children.iter() {
+                graph.push_str(&format!("    n{} -> n{};\n", ids[child], id));
+            }
+        }
+        graph.push_str("}\n");
+        graph
+    }
+
+    // Lists every value in the graph of `slf` once, starting from `slf` and with each value
+    // before its children, along with the position of each value in that list.
+    #[allow(clippy::mutable_key_type)]
+    fn numbered_nodes(slf: &RefValue<T>) -> (Vec<RefValue<T>>, HashMap<RefValue<T>, usize>) {
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        Self::topological_sort(slf, &mut nodes, &mut visited);
+        nodes.reverse();
+
+        let ids = nodes
+            .iter()
+            .enumerate()
+            .map(|(id, node)| (node.clone(), id))
+            .collect();
+        (nodes, ids)
+    }
+}
+
+// Lists `values` for error messages.
+fn describe_values<T: Float>(values: &[RefValue<T>]) -> String {
+    let values: Vec<String> = values.iter().map(RefValue::to_string).collect();
+    format!("[{}]", values.join(", "))
+}
+
+// Escapes the characters which have a meaning inside a DOT record label.
+fn escape_dot_record(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '{' | '}' | '|' | '<' | '>' | '"' | '\\') {
+            escaped.push('\\');
         }
-        next_id
+        escaped.push(c);
     }
+    escaped
 }
 
-impl RefValue {
-    pub fn get(&self) -> &RefCell<Value> {
-        self.0.borrow()
+impl<T: Float> Drop for Value<T> {
+    fn drop(&mut self) {
+        // Dropping a long chain of values recursively would overflow the stack, so children
+        // that are only owned by this value are unlinked and dropped one at a time instead.
+        let mut stack = std::mem::take(&mut self.children);
+        while let Some(child) = stack.pop() {
+            if let Ok(cell) = Shared::try_unwrap(child.0) {
+                stack.append(&mut cell.into_inner().children);
+            }
+        }
+    }
+}
+
+impl<T: Float> RefValue<T> {
+    pub fn get(&self) -> &SharedCell<Value<T>> {
+        &self.0
+    }
+
+    // Borrows the value, failing instead of panicking if it is borrowed mutably. With the `sync`
+    // feature a lock held by another thread is waited for if `wait` is set, as the panicking
+    // functions do, and reported otherwise, as the `try_` functions do.
+    #[cfg_attr(not(feature = "sync"), allow(unused_variables))]
+    pub(crate) fn try_borrow(&self, wait: bool) -> Result<impl Deref<Target = Value<T>> + '_> {
+        #[cfg(feature = "sync")]
+        if wait {
+            return Ok(self.0.borrow());
+        }
+        self.0.try_borrow().map_err(|_| Error::BorrowConflict)
+    }
+
+    // Borrows the value mutably, failing instead of panicking if it is borrowed, see `try_borrow`.
+    #[cfg_attr(not(feature = "sync"), allow(unused_variables))]
+    pub(crate) fn try_borrow_mut(
+        &self,
+        wait: bool,
+    ) -> Result<impl DerefMut<Target = Value<T>> + '_> {
+        #[cfg(feature = "sync")]
+        if wait {
+            return Ok(self.0.borrow_mut());
+        }
+        self.0.try_borrow_mut().map_err(|_| Error::BorrowConflict)
+    }
+
+    /// Names the value, the label is shown by `Display` and the graph exporters.
+    pub fn with_label(self, label: impl Into<String>) -> RefValue<T> {
+        self.get().borrow_mut().set_label(label);
+        self
+    }
+
+    /// Registers `hook` to be called with the gradient of this value during back propagation.
+    ///
+    /// The hook sees the complete gradient of the value before it flows on to its children,
+    /// and may return a replacement, e.g. to clip, mask or reverse it. Hooks run in the order
+    /// they were registered, in `Value::back_propagate`, `Value::back_propagate_accumulate`,
+    /// `Value::back_propagate_seeded` and `Program::back_propagate`. In accumulate mode a
+    /// leaf's hooks only see the gradient of the current pass. The hooks of the parameters
+    /// passed to `MultiLayerPerceptron::par_back_propagate` see the gradient of the whole
+    /// batch. They don't run in `Value::gradients`.
+    pub fn register_hook(&self, hook: impl GradHook<T> + 'static) {
+        self.get().borrow_mut().hooks.0.push(Shared::new(hook));
+    }
+
+    /// Removes every hook registered on this value.
+    pub fn clear_hooks(&self) {
+        self.get().borrow_mut().hooks.0.clear();
+    }
+
+    /// Returns a new leaf holding the same data, cut off from the graph of `self`.
+    pub fn detach(&self) -> RefValue<T> {
+        Value::leaf(self.get().borrow().data)
     }
 }
 
 // Implement Hash and Eq for RefValue
-impl Hash for RefValue {
+impl<T: Float> Hash for RefValue<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        // Hash the pointer address of the Rc
-        Rc::as_ptr(&self.0).hash(state);
+        // Hash the pointer address of the shared pointer
+        Shared::as_ptr(&self.0).hash(state);
     }
 }
 
-impl PartialEq for RefValue {
+impl<T: Float> PartialEq for RefValue<T> {
     fn eq(&self, other: &Self) -> bool {
         // Compare the pointer addresses
-        Rc::ptr_eq(&self.0, &other.0)
+        Shared::ptr_eq(&self.0, &other.0)
     }
 }
 
-impl Eq for RefValue {}
+impl<T: Float> Eq for RefValue<T> {}
 
-impl fmt::Display for RefValue {
+impl<T: Float> fmt::Display for RefValue<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Value(data={:.4}, op={}, grad={:.4}, children={})",
-            self.get().borrow().data,
-            self.get().borrow().op.unwrap_or("?"),
-            self.get().borrow().grad,
-            self.get().borrow().children.len()
-        )
+        write!(f, "{}", *self.get().borrow())
     }
 }
 
-impl fmt::Display for Value {
+impl<T: Float> fmt::Display for Value<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Value(")?;
+        if let Some(label) = &self.label {
+            write!(f, "label={}, ", label)?;
+        }
         write!(
             f,
-            "Value(data={:.4}, op={}, grad={:.4}, children={})",
+            "data={:.4}, op={}, grad={:.4}, children={})",
             self.data,
-            self.op.unwrap_or("?"),
+            self.op.as_ref().map_or("?", Op::name),
             self.grad,
             self.children.len()
         )
     }
 }
 
-impl From<f32> for RefValue {
-    fn from(value: f32) -> Self {
+impl<T: Float> From<T> for RefValue<T> {
+    fn from(value: T) -> Self {
         // TODO: Check if other properties also need to be copied.
-        Value::new(value)
+        Value::leaf(value)
     }
 }
 
-impl From<i32> for RefValue {
+impl<T: Float> From<i32> for RefValue<T> {
     fn from(value: i32) -> Self {
         // TODO: Check if other properties also need to be copied.
-        Value::new(value as f32)
+        Value::leaf(T::from_f64(value as f64))
     }
 }
 
+impl<T: Float> iter::Sum for RefValue<T> {
+    fn sum<I: Iterator<Item = RefValue<T>>>(iter: I) -> Self {
+        Value::sum(iter.collect())
+    }
+}
 
-impl<T: Into<RefValue>> ops::Add<T> for RefValue {
-    type Output = Self;
-
-    fn add(self, rhs: T) -> Self::Output {
-        Value::add(self, rhs.into())
+impl<'a, T: Float> iter::Sum<&'a RefValue<T>> for RefValue<T> {
+    fn sum<I: Iterator<Item = &'a RefValue<T>>>(iter: I) -> Self {
+        Value::sum(iter.cloned().collect())
     }
 }
 
-impl ops::Add<RefValue> for i32 {
-    type Output = RefValue;
+impl<T: Float> iter::Product for RefValue<T> {
+    fn product<I: Iterator<Item = RefValue<T>>>(iter: I) -> Self {
+        Value::product(iter.collect())
+    }
+}
 
-    fn add(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
-        self_as_ref_value + rhs
+impl<'a, T: Float> iter::Product<&'a RefValue<T>> for RefValue<T> {
+    fn product<I: Iterator<Item = &'a RefValue<T>>>(iter: I) -> Self {
+        Value::product(iter.cloned().collect())
     }
 }
 
-impl ops::Add<RefValue> for f32 {
-    type Output = RefValue;
+impl<T: Float, R: Into<RefValue<T>>> ops::Add<R> for RefValue<T> {
+    type Output = Self;
 
-    fn add(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
-        self_as_ref_value + rhs
+    fn add(self, rhs: R) -> Self::Output {
+        Value::add(self, rhs.into())
     }
 }
 
-impl<T: Into<RefValue>> ops::Sub<T> for RefValue {
-    type Output = RefValue;
+impl<T: Float> ops::Add<RefValue<T>> for i32 {
+    type Output = RefValue<T>;
 
-    fn sub(self, rhs: T) -> Self::Output {
-        let negative_rhs = rhs.into() * Value::new(-1.0);
-        self + negative_rhs//(rhs * -1.0)
+    fn add(self, rhs: RefValue<T>) -> Self::Output {
+        let self_as_ref_value: RefValue<T> = self.into();
+        self_as_ref_value + rhs
     }
 }
 
-impl ops::Sub<RefValue> for i32 {
-    type Output = RefValue;
+impl<T: Float, R: Into<RefValue<T>>> ops::Sub<R> for RefValue<T> {
+    type Output = RefValue<T>;
 
-    fn sub(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
-        self_as_ref_value - rhs
+    fn sub(self, rhs: R) -> Self::Output {
+        Value::sub(self, rhs.into())
     }
 }
 
-impl ops::Sub<RefValue> for f32 {
-    type Output = RefValue;
+impl<T: Float> ops::Sub<RefValue<T>> for i32 {
+    type Output = RefValue<T>;
 
-    fn sub(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
+    fn sub(self, rhs: RefValue<T>) -> Self::Output {
+        let self_as_ref_value: RefValue<T> = self.into();
         self_as_ref_value - rhs
     }
 }
 
-impl<T: Into<RefValue>> ops::Mul<T> for RefValue {
+impl<T: Float, R: Into<RefValue<T>>> ops::Mul<R> for RefValue<T> {
     type Output = Self;
 
-    fn mul(self, rhs: T) -> Self::Output {
+    fn mul(self, rhs: R) -> Self::Output {
         Value::mul(self, rhs.into())
     }
 }
 
-impl ops::Mul<RefValue> for i32 {
-    type Output = RefValue;
+impl<T: Float> ops::Mul<RefValue<T>> for i32 {
+    type Output = RefValue<T>;
 
-    fn mul(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
+    fn mul(self, rhs: RefValue<T>) -> Self::Output {
+        let self_as_ref_value: RefValue<T> = self.into();
         self_as_ref_value * rhs
     }
 }
 
-impl ops::Mul<RefValue> for f32 {
-    type Output = RefValue;
+impl<T: Float, R: Into<RefValue<T>>> ops::Div<R> for RefValue<T> {
+    type Output = Self;
 
-    fn mul(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
-        self_as_ref_value * rhs
+    fn div(self, rhs: R) -> Self::Output {
+        Value::div(self, rhs.into())
     }
 }
 
-impl<T: Into<RefValue>> ops::Div<T> for RefValue {
-    type Output = Self;
+impl<T: Float> ops::Neg for RefValue<T> {
+    type Output = RefValue<T>;
 
-    fn div(self, rhs: T) -> Self::Output {
-        let rhs = rhs.into();
-        self * Value::pow(rhs, -1.0)
+    fn neg(self) -> Self::Output {
+        Value::neg(self)
     }
-
 }
 
-impl ops::Div<RefValue> for i32 {
-    type Output = RefValue;
+impl<T: Float> ops::Div<RefValue<T>> for i32 {
+    type Output = RefValue<T>;
 
-    fn div(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
+    fn div(self, rhs: RefValue<T>) -> Self::Output {
+        let self_as_ref_value: RefValue<T> = self.into();
         self_as_ref_value / rhs
     }
 }
 
-impl ops::Div<RefValue> for f32 {
-    type Output = RefValue;
+// Implements the operators with a float scalar on the left hand side, which can't be
+// done generically over `T` as the scalar type itself is foreign.
+macro_rules! impl_scalar_lhs_ops {
+    ($t:ty) => {
+        impl ops::Add<RefValue<$t>> for $t {
+            type Output = RefValue<$t>;
 
-    fn div(self, rhs: RefValue) -> Self::Output {
-        let self_as_ref_value: RefValue = self.into();
-        self_as_ref_value / rhs
-    }
-}
\ No newline at end of file
+            fn add(self, rhs: RefValue<$t>) -> Self::Output {
+                let self_as_ref_value: RefValue<$t> = self.into();
+                self_as_ref_value + rhs
+            }
+        }
+
+        impl ops::Sub<RefValue<$t>> for $t {
+            type Output = RefValue<$t>;
+
+            fn sub(self, rhs: RefValue<$t>) -> Self::Output {
+                let self_as_ref_value: RefValue<$t> = self.into();
+                self_as_ref_value - rhs
+            }
+        }
+
+        impl ops::Mul<RefValue<$t>> for $t {
+            type Output = RefValue<$t>;
+
+            fn mul(self, rhs: RefValue<$t>) -> Self::Output {
+                let self_as_ref_value: RefValue<$t> = self.into();
+                self_as_ref_value * rhs
+            }
+        }
+
+        impl ops::Div<RefValue<$t>> for $t {
+            type Output = RefValue<$t>;
+
+            fn div(self, rhs: RefValue<$t>) -> Self::Output {
+                let self_as_ref_value: RefValue<$t> = self.into();
+                self_as_ref_value / rhs
+            }
+        }
+    };
+}
+
+impl_scalar_lhs_ops!(f32);
+impl_scalar_lhs_ops!(f64);
@@ -0,0 +1,146 @@
+use micrograd_rs::anomaly::{detect_anomaly, is_anomaly_detection_enabled, AnomalyDetectionGuard};
+use micrograd_rs::error::Error;
+use micrograd_rs::neuron::MultiLayerPerceptron;
+use micrograd_rs::op::Op;
+use micrograd_rs::program::Program;
+use micrograd_rs::value::Value;
+use std::panic::{self, AssertUnwindSafe};
+
+#[cfg(test)]
+mod anomaly_tests {
+    use super::*;
+
+    // Runs `f`, expecting it to panic, and returns the panic message.
+    fn panic_message<F: FnOnce()>(f: F) -> String {
+        let payload = panic::catch_unwind(AssertUnwindSafe(f)).expect_err("expected a panic");
+        match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload.downcast::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(!is_anomaly_detection_enabled());
+
+        let x = Value::log(Value::new(-1.0_f32));
+        assert!(x.get().borrow().data.is_nan());
+    }
+
+    #[test]
+    fn test_guard_nesting() {
+        {
+            let _outer = AnomalyDetectionGuard::new();
+            {
+                let _inner = AnomalyDetectionGuard::new();
+                assert!(is_anomaly_detection_enabled());
+            }
+            assert!(is_anomaly_detection_enabled());
+        }
+        assert!(!is_anomaly_detection_enabled());
+
+        assert!(detect_anomaly(is_anomaly_detection_enabled));
+    }
+
+    #[test]
+    fn test_forward_anomaly() {
+        let a = Value::new(-1.0).with_label("a");
+        let message = panic_message(|| {
+            detect_anomaly(|| Value::log(a.clone()));
+        });
+
+        assert!(message.contains("`log` produced a NaN output in the forward pass"), "{}", message);
+        assert!(message.contains("label=a, data=-1.0000"), "{}", message);
+        // The guard is restored after the panic.
+        assert!(!is_anomaly_detection_enabled());
+    }
+
+    #[test]
+    fn test_forward_infinite() {
+        let message = panic_message(|| {
+            detect_anomaly(|| Value::new(1.0) / Value::new(0.0));
+        });
+
+        assert!(message.contains("`/` produced an infinite output"), "{}", message);
+    }
+
+    #[test]
+    fn test_backward_anomaly() {
+        // sqrt(0) is fine but its gradient is infinite.
+        let x = Value::new(0.0).with_label("x");
+        let y = (Value::sqrt(x.clone()) * 2.0).with_label("loss");
+
+        let message = panic_message(|| detect_anomaly(|| Value::back_propagate(&y)));
+        assert!(
+            message.contains("`sqrt` produced an infinite gradient for its input 0"),
+            "{}",
+            message
+        );
+        assert!(message.contains("gradient of the output: 2"), "{}", message);
+        assert!(message.contains("path from the root: loss -> sqrt"), "{}", message);
+    }
+
+    #[test]
+    fn test_backward_without_detection() {
+        let x = Value::new(0.0_f32);
+        let y = Value::sqrt(x.clone()) * 2.0;
+        Value::back_propagate(&y);
+
+        assert!(x.get().borrow().grad.is_infinite());
+    }
+
+    #[test]
+    fn test_program_anomaly() {
+        let x = Value::new(1.0);
+        let y = Value::sqrt(x.clone()).with_label("root");
+        let mut program = Program::compile(&y, std::slice::from_ref(&x));
+
+        let message = panic_message(|| {
+            detect_anomaly(|| program.forward(&[-4.0]));
+        });
+        assert!(message.contains("`sqrt` produced a NaN output"), "{}", message);
+        assert!(message.contains("[-4.0]"), "{}", message);
+
+        program.forward(&[0.0]);
+        let message = panic_message(|| detect_anomaly(|| program.back_propagate()));
+        assert!(message.contains("path from the root: root"), "{}", message);
+    }
+
+    #[test]
+    fn test_try_anomaly() {
+        let x = Value::new(0.0).with_label("x");
+        let y = (Value::sqrt(x.clone()) * 2.0).with_label("loss");
+
+        let err = detect_anomaly(|| Value::try_back_propagate(&y)).unwrap_err();
+        match &err {
+            Error::Anomaly { op, grad, path, .. } => {
+                assert_eq!(op, "sqrt");
+                assert_eq!(grad.as_deref(), Some("2"));
+                assert_eq!(path, &["loss", "sqrt"]);
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+        assert!(err.to_string().contains("`sqrt` produced an infinite gradient"), "{}", err);
+
+        let err = detect_anomaly(|| Value::try_apply(Op::Log, vec![Value::new(-1.0)])).unwrap_err();
+        assert!(matches!(err, Error::Anomaly { grad: None, .. }), "{:?}", err);
+
+        let mlp = MultiLayerPerceptron::new(2, vec![1]);
+        let err = detect_anomaly(|| mlp.try_forward(&[Value::new(f32::NAN), Value::new(1.0)]));
+        assert!(matches!(err, Err(Error::Anomaly { .. })), "{:?}", err);
+    }
+
+    #[test]
+    fn test_program_try_anomaly() {
+        let x = Value::new(1.0);
+        let y = Value::sqrt(x.clone()).with_label("root");
+        let mut program = Program::compile(&y, std::slice::from_ref(&x));
+
+        let err = detect_anomaly(|| program.try_forward(&[-4.0])).unwrap_err();
+        assert!(err.to_string().contains("`sqrt` produced a NaN output"), "{}", err);
+
+        program.forward(&[0.0]);
+        let err = detect_anomaly(|| program.try_back_propagate()).unwrap_err();
+        assert!(matches!(&err, Error::Anomaly { path, .. } if path == &["root"]), "{:?}", err);
+    }
+}
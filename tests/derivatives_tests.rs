@@ -0,0 +1,96 @@
+use micrograd_rs::derivatives::{hessian, jacobian};
+use micrograd_rs::neuron::MultiLayerPerceptron;
+use micrograd_rs::value::{RefValue, Value};
+
+#[cfg(test)]
+mod derivatives_tests {
+    use super::*;
+
+    #[test]
+    fn test_jacobian() {
+        let x = Value::leaf(2.0_f32);
+        let y = Value::leaf(3.0);
+        let z = Value::leaf(5.0);
+        let outputs = vec![
+            x.clone() * y.clone(),
+            x.clone() + Value::pow(y.clone(), 2.0),
+            Value::exp(x.clone()),
+        ];
+
+        let jac = jacobian(&outputs, &[x, y, z]);
+        assert_eq!(jac[0], vec![3.0, 2.0, 0.0]);
+        assert_eq!(jac[1], vec![1.0, 6.0, 0.0]);
+        assert_eq!(jac[2], vec![2.0_f32.exp(), 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_jacobian_mlp() {
+        let mlp = MultiLayerPerceptron::<f64>::random(3, vec![4, 2]);
+        let inputs = [0.3, -0.7, 1.1];
+        let x: Vec<RefValue<f64>> = inputs.iter().map(|&v| Value::leaf(v)).collect();
+
+        let jac = jacobian(&mlp.forward(&x), &x);
+        assert_eq!((jac.len(), jac[0].len()), (2, 3));
+
+        // Compare against central finite differences, one column per input.
+        let eps = 1e-6;
+        let columns: Vec<Vec<f64>> = (0..3)
+            .map(|j| {
+                let shifted = |shift: f64| {
+                    let x: Vec<RefValue<f64>> = inputs
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &v)| Value::leaf(if i == j { v + shift } else { v }))
+                        .collect();
+                    mlp.forward(&x)
+                };
+                let (plus, minus) = (shifted(eps), shifted(-eps));
+                plus.iter()
+                    .zip(&minus)
+                    .map(|(p, m)| (p.get().borrow().data - m.get().borrow().data) / (2.0 * eps))
+                    .collect()
+            })
+            .collect();
+        for (i, row) in jac.iter().enumerate() {
+            for (j, &analytic) in row.iter().enumerate() {
+                let numeric = columns[j][i];
+                assert!((analytic - numeric).abs() < 1e-6, "{} != {}", analytic, numeric);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hessian() {
+        // x^2 y + sin(y) z^3
+        let f = |v: &[RefValue<f64>]| {
+            Value::pow(v[0].clone(), 2.0) * v[1].clone()
+                + Value::sin(v[1].clone()) * Value::pow(v[2].clone(), 3.0)
+        };
+        let (x, y, z) = (1.0_f64, 2.0_f64, 3.0_f64);
+
+        let hess = hessian(f, &[x, y, z]);
+        let expected = [
+            [2.0 * y, 2.0 * x, 0.0],
+            [2.0 * x, -y.sin() * z.powi(3), 3.0 * y.cos() * z.powi(2)],
+            [0.0, 3.0 * y.cos() * z.powi(2), 6.0 * y.sin() * z],
+        ];
+        for (i, row) in hess.iter().enumerate() {
+            for (j, &value) in row.iter().enumerate() {
+                assert!((value - expected[i][j]).abs() < 1e-9, "[{}][{}]", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hessian_linear() {
+        let hess = hessian(|v: &[RefValue]| v[0].clone() * 2.0 + v[1].clone() * 3.0, &[1.0, -1.0]);
+        assert_eq!(hess, vec![vec![0.0, 0.0], vec![0.0, 0.0]]);
+    }
+
+    #[test]
+    fn test_hessian_identity_gradient() {
+        // The gradient of x * y with respect to x is y itself.
+        let hess = hessian(|v: &[RefValue]| v[0].clone() * v[1].clone(), &[2.0, 3.0]);
+        assert_eq!(hess, vec![vec![0.0, 1.0], vec![1.0, 0.0]]);
+    }
+}
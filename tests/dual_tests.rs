@@ -0,0 +1,67 @@
+use micrograd_rs::dual::{jvp, Dual};
+use micrograd_rs::value::Value;
+
+#[cfg(test)]
+mod dual_tests {
+
+    use super::*;
+
+    #[test]
+    fn test_operators() {
+        let a = Dual::constant(2.0);
+        let b = Dual::constant(3.0);
+        let c = Dual::constant(4.0);
+        let d = Dual::constant(5.0);
+
+        // (2 + 3) * (4 - 5) / 2
+        let x = (a + b) * (c - d) / 2.0;
+
+        assert_eq!(x.value, -2.5);
+        assert_eq!(x.deriv, 0.0);
+    }
+
+    #[test]
+    fn test_scalar_operations() {
+        let a = Dual::variable(2.0);
+        let x = 3 + a * 4.0 - 2;
+
+        assert_eq!(x.value, 9.0);
+        assert_eq!(x.deriv, 4.0);
+    }
+
+    #[test]
+    fn test_pow() {
+        let x = Dual::variable(2.0).pow(3.0);
+
+        assert_eq!(x.value, 8.0);
+        assert_eq!(x.deriv, 12.0);
+    }
+
+    #[test]
+    fn test_matches_value() {
+        let f = |a: Dual, b: Dual| (a * b + 0.2).tanh() + a.exp() - b.pow(2.0) / a + (a - b).relu();
+        let dual_a = f(Dual::variable(0.5), Dual::constant(-0.3));
+        let dual_b = f(Dual::constant(0.5), Dual::variable(-0.3));
+
+        let (a, b) = (Value::new(0.5_f32), Value::new(-0.3_f32));
+        let y = Value::tanh(a.clone() * b.clone() + 0.2) + Value::exp(a.clone())
+            - Value::pow(b.clone(), 2.0) / a.clone()
+            + Value::relu(a.clone() - b.clone());
+        Value::back_propagate(&y);
+
+        assert!((dual_a.value - y.get().borrow().data).abs() < 1e-6);
+        assert!((dual_a.deriv - a.get().borrow().grad).abs() < 1e-5);
+        assert!((dual_b.deriv - b.get().borrow().grad).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_jvp() {
+        let f = |x: &[Dual<f64>]| vec![x[0] * x[1], x[0] + x[1], x[0].tanh()];
+
+        let (outputs, tangents) = jvp(f, &[2.0, 3.0], &[1.0, 0.5]);
+
+        assert_eq!(outputs[..2], [6.0, 5.0]);
+        assert_eq!(tangents[..2], [3.0 + 2.0 * 0.5, 1.5]);
+        assert!((tangents[2] - (1.0 - 2.0_f64.tanh().powi(2))).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,197 @@
+use micrograd_rs::error::Error;
+use micrograd_rs::neuron::{NetworkParameters, MultiLayerPerceptron, Neuron};
+use micrograd_rs::op::{CustomOp, Op};
+use micrograd_rs::program::Program;
+use micrograd_rs::value::Value;
+
+// An operation which forgets to return its local gradients.
+#[derive(Debug)]
+struct NoGrads;
+
+impl CustomOp for NoGrads {
+    fn name(&self) -> &str {
+        "no_grads"
+    }
+
+    fn forward(&self, inputs: &[f32]) -> f32 {
+        inputs[0]
+    }
+
+    fn local_grads(&self, _inputs: &[f32], _output: f32) -> Vec<f32> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+
+    #[test]
+    fn test_try_back_propagate() {
+        let x = Value::new(2.0);
+        let y = x.clone() * x.clone() + 1.0;
+
+        assert_eq!(Value::try_back_propagate(&y), Ok(()));
+        assert_eq!(x.get().borrow().grad, 4.0);
+    }
+
+    #[test]
+    fn test_arity() {
+        let x = Value::new(2.0);
+        let y = x.clone() + 1.0;
+        y.get().borrow_mut().children.pop();
+        x.get().borrow_mut().grad = 5.0;
+
+        let err = Value::try_back_propagate(&y).unwrap_err();
+        assert_eq!(err, Error::Arity { op: String::from("+"), children: 1 });
+        assert_eq!(err.to_string(), "`+` can't be applied to 1 children");
+        // The graph is checked before any gradient changes.
+        assert_eq!(x.get().borrow().grad, 5.0);
+    }
+
+    #[test]
+    fn test_local_grads() {
+        let y = Value::custom(NoGrads, vec![Value::new(1.0)]);
+
+        assert_eq!(
+            Value::try_back_propagate(&y),
+            Err(Error::LocalGrads { op: String::from("no_grads"), children: 1, grads: 0 })
+        );
+        let mut program = Program::compile(&y, &[]);
+        assert_eq!(
+            program.try_back_propagate(),
+            Err(Error::LocalGrads { op: String::from("no_grads"), children: 1, grads: 0 })
+        );
+    }
+
+    #[test]
+    fn test_cycle() {
+        let x = Value::new(0.5);
+        let y = Value::tanh(x.clone()) * 2.0;
+        x.get().borrow_mut().children.push(y.clone());
+
+        assert_eq!(Value::try_back_propagate(&y), Err(Error::Cycle));
+        // Break the cycle so the values can be dropped.
+        x.get().borrow_mut().children.clear();
+    }
+
+    #[test]
+    #[should_panic(expected = "the graph has a cycle")]
+    fn test_back_propagate_cycle() {
+        let x = Value::new(0.5);
+        let y = Value::tanh(x.clone());
+        x.get().borrow_mut().children.push(y.clone());
+
+        Value::back_propagate(&y);
+    }
+
+    #[test]
+    fn test_borrow_conflict() {
+        let x = Value::new(2.0);
+        let y = Value::exp(x.clone());
+
+        {
+            let _guard = x.get().borrow_mut();
+            assert_eq!(Value::try_back_propagate(&y), Err(Error::BorrowConflict));
+        }
+        assert_eq!(Value::try_back_propagate(&y), Ok(()));
+    }
+
+    #[test]
+    fn test_forward_borrow_conflict() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let x = vec![Value::new(1.0), Value::new(2.0)];
+
+        {
+            let _guard = x[1].get().borrow_mut();
+            assert_eq!(mlp.try_forward(&x), Err(Error::BorrowConflict));
+        }
+        let parameters = mlp.parameters();
+        {
+            let _guard = parameters[0].get().borrow_mut();
+            assert_eq!(mlp.try_forward(&x), Err(Error::BorrowConflict));
+        }
+        assert!(mlp.try_forward(&x).is_ok());
+    }
+
+    #[test]
+    fn test_try_apply() {
+        let x = Value::new(2.0);
+
+        assert_eq!(
+            Value::try_apply(Op::Add, vec![x.clone()]).unwrap_err(),
+            Error::Arity { op: String::from("+"), children: 1 }
+        );
+        {
+            let _guard = x.get().borrow_mut();
+            assert_eq!(
+                Value::try_apply(Op::Exp, vec![x.clone()]).unwrap_err(),
+                Error::BorrowConflict
+            );
+        }
+        assert_eq!(Value::try_apply(Op::Exp, vec![x]).unwrap().get().borrow().data, 2.0_f32.exp());
+    }
+
+    #[test]
+    fn test_program_errors() {
+        let x = Value::new(2.0);
+        let w = Value::new(3.0);
+        let mut program = Program::compile(&(x.clone() * w.clone()), std::slice::from_ref(&x));
+
+        assert_eq!(
+            program.try_forward(&[]),
+            Err(Error::ShapeMismatch { expected: 1, actual: 0 })
+        );
+        {
+            let _guard = w.get().borrow_mut();
+            assert_eq!(program.try_forward(&[1.0]), Err(Error::BorrowConflict));
+        }
+        assert_eq!(program.try_forward(&[1.0]), Ok(3.0));
+    }
+
+    #[test]
+    fn test_neuron_shape_mismatch() {
+        let neuron = Neuron::new(3);
+        let x = vec![Value::new(1.0), Value::new(2.0)];
+
+        let err = neuron.try_forward(&x).unwrap_err();
+        assert_eq!(err, Error::ShapeMismatch { expected: 3, actual: 2 });
+        assert_eq!(err.to_string(), "wrong number of inputs: expected 3 but got 2");
+
+        let mlp = MultiLayerPerceptron::new(3, vec![4, 1]);
+        assert_eq!(
+            mlp.try_forward(&x),
+            Err(Error::LayerShapeMismatch { layer: 0, expected: 3, actual: 2 })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of inputs for layer 0: expected 2 but got 1")]
+    fn test_forward_panics() {
+        let mlp = MultiLayerPerceptron::new(2, vec![1]);
+        mlp.forward(&[Value::new(1.0)]);
+    }
+
+    #[test]
+    fn test_try_train() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let xs = vec![
+            vec![Value::new(0.0), Value::new(1.0)],
+            vec![Value::new(1.0), Value::new(0.0)],
+        ];
+
+        assert_eq!(
+            mlp.try_train(0.1, 10, xs.clone(), vec![Value::new(1.0)]),
+            Err(Error::ShapeMismatch { expected: 2, actual: 1 })
+        );
+        assert_eq!(
+            mlp.try_train(0.1, 10, vec![vec![Value::new(1.0)]], vec![Value::new(1.0)]),
+            Err(Error::LayerShapeMismatch { layer: 0, expected: 2, actual: 1 })
+        );
+        assert_eq!(mlp.try_train(0.1, 10, xs, vec![Value::new(1.0), Value::new(-1.0)]), Ok(()));
+
+        let empty = MultiLayerPerceptron::new(2, vec![0]);
+        let xs = vec![vec![Value::new(0.0), Value::new(1.0)]];
+        assert_eq!(empty.try_train(0.1, 10, xs, vec![Value::new(1.0)]), Err(Error::EmptyOutput));
+    }
+}
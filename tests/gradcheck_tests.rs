@@ -0,0 +1,174 @@
+use micrograd_rs::gradcheck::{gradcheck, gradcheck_parameters};
+use micrograd_rs::neuron::{MultiLayerPerceptron, Neuron};
+use micrograd_rs::op::CustomOp;
+use micrograd_rs::value::{RefValue, Value};
+
+// Softsign with a deliberately wrong gradient.
+#[derive(Debug)]
+struct BrokenSoftsign;
+
+impl CustomOp<f64> for BrokenSoftsign {
+    fn name(&self) -> &str {
+        "softsign"
+    }
+
+    fn forward(&self, inputs: &[f64]) -> f64 {
+        inputs[0] / (1.0 + inputs[0].abs())
+    }
+
+    fn local_grads(&self, inputs: &[f64], _output: f64) -> Vec<f64> {
+        vec![1.0 / (1.0 + inputs[0].abs())]
+    }
+}
+
+#[cfg(test)]
+mod gradcheck_tests {
+
+    use super::*;
+
+    #[test]
+    fn test_gradcheck() {
+        let f = |x: &[RefValue<f64>]| {
+            Value::tanh(x[0].clone() * x[1].clone() + x[2].clone()) + Value::exp(x[0].clone())
+                - Value::pow(x[1].clone(), 3.0) / x[2].clone()
+        };
+
+        let report = gradcheck(f, &[0.5, -0.3, 0.8], 1e-6);
+
+        assert_eq!(report.entries.len(), 3);
+        assert!(report.passed(1e-6, 1e-5), "{:?}", report);
+    }
+
+    #[test]
+    fn test_gradcheck_detects_wrong_gradient() {
+        let f = |x: &[RefValue<f64>]| Value::custom(BrokenSoftsign, vec![x[0].clone()]);
+
+        let report = gradcheck(f, &[2.0], 1e-6);
+
+        assert!(!report.passed(1e-6, 1e-5));
+        assert!((report.entries[0].analytic - 1.0 / 3.0).abs() < 1e-12);
+        assert!((report.entries[0].numeric - 1.0 / 9.0).abs() < 1e-6);
+        assert!((report.max_rel_error() - 2.0 / 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_gradcheck_parameters() {
+        let mlp = MultiLayerPerceptron::<f64>::random(2, vec![3, 1]);
+        let xs = [[0.5, -1.0], [1.0, 0.25], [-0.5, 0.75]];
+        let ys = [1.0, -1.0, 1.0];
+        let loss = || {
+            xs.iter()
+                .zip(ys)
+                .map(|(x, y)| {
+                    let x: Vec<RefValue<f64>> = x.iter().map(|&v| Value::leaf(v)).collect();
+                    Value::pow(mlp.forward(&x)[0].clone() - y, 2.0)
+                })
+                .fold(Value::leaf(0.0), |acc, l| acc + l)
+        };
+
+        let report = gradcheck_parameters(&mlp, loss, 1e-6);
+
+        assert_eq!(report.entries.len(), 13);
+        assert!(report.passed(1e-6, 1e-5), "{:?}", report);
+    }
+
+    #[test]
+    fn test_gradcheck_parameters_unused() {
+        let neuron = Neuron::<f64>::random(2);
+        // A gradient left over from an earlier pass on a weight the loss doesn't use.
+        neuron.weights[1].get().borrow_mut().grad = 5.0;
+        let loss = || Value::pow(neuron.weights[0].clone(), 2.0) + neuron.bias.clone();
+
+        let report = gradcheck_parameters(&neuron, loss, 1e-6);
+
+        // Parameters are ordered bias first.
+        assert_eq!(report.entries[2].analytic, 0.0);
+        assert!(report.passed(1e-6, 1e-5), "{:?}", report);
+    }
+
+    type UnaryOp = fn(RefValue<f64>) -> RefValue<f64>;
+    type BinaryOp = fn(RefValue<f64>, RefValue<f64>) -> RefValue<f64>;
+
+    #[test]
+    fn test_gradcheck_elementwise_ops() {
+        let unary: Vec<(&str, UnaryOp)> = vec![
+            ("log", Value::log),
+            ("sqrt", Value::sqrt),
+            ("abs", Value::abs),
+            ("neg", Value::neg),
+            ("sin", Value::sin),
+            ("cos", Value::cos),
+            ("sigmoid", Value::sigmoid),
+            ("softplus", Value::softplus),
+            ("leaky_relu", |x| Value::leaky_relu(x, 0.1)),
+            ("elu", |x| Value::elu(x, 1.5)),
+            ("gelu", Value::gelu),
+            ("silu", Value::silu),
+            ("clamp", |x| Value::clamp(x, -1.0, 1.0)),
+        ];
+        for (name, op) in unary {
+            for x in [-1.7, -0.4, 0.6, 2.3] {
+                // log and sqrt are only defined for positive inputs.
+                let x: f64 = if name == "log" || name == "sqrt" { x * x } else { x };
+                let report = gradcheck(|v| op(v[0].clone()), &[x], 1e-6);
+                assert!(report.passed(1e-6, 1e-5), "{} at {}: {:?}", name, x, report);
+
+                // The gradient built as a graph must agree with back propagation.
+                let v = Value::leaf(x);
+                let grad = Value::gradients(&op(v.clone()), std::slice::from_ref(&v)).remove(0);
+                let data = grad.get().borrow().data;
+                assert!((data - report.entries[0].analytic).abs() < 1e-12, "{} at {}", name, x);
+            }
+        }
+
+        let binary: Vec<(&str, BinaryOp)> = vec![
+            ("min", Value::min),
+            ("max", Value::max),
+            ("pow_value", Value::pow_value),
+        ];
+        for (name, op) in binary {
+            let report = gradcheck(|v| op(v[0].clone(), v[1].clone()), &[1.3, 0.7], 1e-6);
+            assert!(report.passed(1e-6, 1e-5), "{}: {:?}", name, report);
+        }
+    }
+
+    #[test]
+    fn test_second_derivatives_of_elementwise_ops() {
+        let ops: Vec<(&str, UnaryOp)> = vec![
+            ("log", Value::log),
+            ("sqrt", Value::sqrt),
+            ("sin", Value::sin),
+            ("cos", Value::cos),
+            ("sigmoid", Value::sigmoid),
+            ("softplus", Value::softplus),
+            ("elu", |x| Value::elu(x, 1.5)),
+            ("gelu", Value::gelu),
+            ("silu", Value::silu),
+        ];
+        for (name, op) in ops {
+            for x in [-0.8, 0.4, 1.9] {
+                let x: f64 = if name == "log" || name == "sqrt" { x * x } else { x };
+                // Check the back propagated gradient of the first derivative.
+                let derivative =
+                    |v: &[RefValue<f64>]| Value::gradients(&op(v[0].clone()), v).remove(0);
+                let report = gradcheck(derivative, &[x], 1e-5);
+                assert!(report.passed(1e-5, 1e-4), "{} at {}: {:?}", name, x, report);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gradcheck_softmax() {
+        let logits = [0.2, -1.3, 2.1, 0.7];
+        for k in 0..logits.len() {
+            let report = gradcheck(|v| Value::softmax(v)[k].clone(), &logits, 1e-6);
+            assert!(report.passed(1e-6, 1e-5), "softmax {}: {:?}", k, report);
+
+            let report = gradcheck(|v| Value::log_softmax(v)[k].clone(), &logits, 1e-6);
+            assert!(report.passed(1e-6, 1e-5), "log_softmax {}: {:?}", k, report);
+        }
+
+        let report = gradcheck(Value::logsumexp, &logits, 1e-6);
+        assert!(report.passed(1e-6, 1e-5), "{:?}", report);
+    }
+}
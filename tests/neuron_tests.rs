@@ -1,9 +1,10 @@
-use env_logger;
 use log::debug;
-use micrograd_rs::neuron::{MultiLayerPerceptron, NetworkParameters, Neuron};
+use micrograd_rs::error::Error;
+use micrograd_rs::neuron::{Layer, MultiLayerPerceptron, NetworkParameters, Neuron};
 use micrograd_rs::value::{Value, RefValue};
 use rand::Rng;
 use rand::seq::SliceRandom;
+use std::sync::{Arc, Mutex};
 
 #[cfg(test)]
 mod neuron_tests {
@@ -21,6 +22,19 @@ mod neuron_tests {
         assert!(neuron.bias.get().borrow().data >= -1.0 && neuron.bias.get().borrow().data <= 1.0);
     }
 
+    #[test]
+    fn test_from_parameters() {
+        let neuron =
+            Neuron::from_parameters(vec![Value::new(0.5), Value::new(-1.0)], Value::new(0.25));
+        let output = neuron.forward(&[Value::new(2.0), Value::new(1.0)]);
+        assert!((output.get().borrow().data - 0.25_f32.tanh()).abs() < 1e-6);
+
+        let layer = Layer::from_neurons(vec![neuron.clone(), neuron]);
+        assert_eq!((layer.in_features(), layer.out_features()), (2, 2));
+        let mlp = MultiLayerPerceptron { layers: vec![layer] };
+        assert_eq!(mlp.parameters().len(), 6);
+    }
+
     #[test]
     fn test_neuron_forward() {
         let neuron = Neuron::new(2);
@@ -29,6 +43,114 @@ mod neuron_tests {
         assert!(output.get().borrow().data >= -1.0 && output.get().borrow().data <= 1.0);
     }
 
+    #[test]
+    fn test_features() {
+        let neuron = Neuron::new(3);
+        assert_eq!((neuron.in_features(), neuron.out_features()), (3, 1));
+
+        let layer = Layer::new(3, 4);
+        assert_eq!((layer.in_features(), layer.out_features()), (3, 4));
+        let empty = Layer::new(3, 0);
+        assert_eq!((empty.in_features(), empty.out_features()), (0, 0));
+
+        // The features follow the neurons when they are replaced.
+        let mut layer = layer;
+        layer.neurons = vec![Neuron::new(5), Neuron::new(5)];
+        assert_eq!((layer.in_features(), layer.out_features()), (5, 2));
+
+        let mlp = MultiLayerPerceptron::new(3, vec![4, 4, 2]);
+        assert_eq!((mlp.in_features(), mlp.out_features()), (3, 2));
+    }
+
+    #[test]
+    fn test_input_length_validated() {
+        let neuron = Neuron::new(3);
+        let x = vec![Value::new(1.0), Value::new(2.0), Value::new(3.0), Value::new(4.0)];
+        assert_eq!(
+            neuron.try_forward(&x),
+            Err(Error::ShapeMismatch { expected: 3, actual: 4 })
+        );
+
+        let layer = Layer::new(3, 2);
+        assert_eq!(
+            layer.try_forward(&x),
+            Err(Error::ShapeMismatch { expected: 3, actual: 4 })
+        );
+
+        let mut mlp = MultiLayerPerceptron::new(4, vec![3, 1]);
+        assert!(mlp.try_forward(&x).is_ok());
+        assert_eq!(
+            mlp.try_forward(&x[..2]),
+            Err(Error::LayerShapeMismatch { layer: 0, expected: 4, actual: 2 })
+        );
+
+        // A mismatched hidden layer is reported by its index.
+        mlp.layers[1] = Layer::new(5, 1);
+        let err = mlp.try_forward(&x).unwrap_err();
+        assert_eq!(err, Error::LayerShapeMismatch { layer: 1, expected: 5, actual: 3 });
+        assert_eq!(err.to_string(), "wrong number of inputs for layer 1: expected 5 but got 3");
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of inputs: expected 2 but got 3")]
+    fn test_neuron_forward_wrong_length() {
+        let neuron = Neuron::new(2);
+        neuron.forward(&[Value::new(1.0), Value::new(2.0), Value::new(3.0)]);
+    }
+
+    #[test]
+    fn test_neuron_forward_is_fused() {
+        let neuron = Neuron::new(3);
+        let input = vec![Value::new(1.0), Value::new(2.0), Value::new(3.0)];
+        let output = neuron.forward(&input);
+
+        // tanh(dot(x, w) + b)
+        let weighted_sum = output.get().borrow().children[0].clone();
+        let dot = weighted_sum.get().borrow().children[0].clone();
+        assert_eq!(dot.get().borrow().children.len(), 6);
+        assert_eq!(
+            dot.get().borrow().op().map(|op| op.name().to_string()),
+            Some("dot".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parameter_labels() {
+        let neuron = Neuron::new(2);
+        let labels: Vec<String> = neuron
+            .parameters()
+            .iter()
+            .map(|p| p.get().borrow().label().unwrap().to_string())
+            .collect();
+        assert_eq!(labels, vec!["b", "w0", "w1"]);
+
+        let mlp = MultiLayerPerceptron::new(3, vec![4, 4, 1]);
+        let w = &mlp.layers[1].neurons[3].weights[2];
+        assert_eq!(w.get().borrow().label(), Some("layer1.neuron3.w2"));
+        assert_eq!(mlp.layers[2].neurons[0].bias.get().borrow().label(), Some("layer2.neuron0.b"));
+        assert!(mlp.to_string().contains("label=layer0.neuron0.w0"));
+    }
+
+    #[test]
+    fn test_forward_hooks() {
+        let activations = Arc::new(Mutex::new(vec![]));
+        let mut layer = Layer::new(2, 3);
+        let seen = activations.clone();
+        layer.neurons[1].register_forward_hook(move |inputs, outputs| {
+            assert_eq!(inputs.len(), 2);
+            seen.lock().unwrap().push(outputs[0].get().borrow().data);
+        });
+        let seen = activations.clone();
+        layer.register_forward_hook(move |_, outputs| {
+            assert_eq!(outputs.len(), 3);
+            seen.lock().unwrap().extend(outputs.iter().map(|o| o.get().borrow().data));
+        });
+
+        let outputs = layer.forward(&[Value::new(1.0), Value::new(-1.0)]);
+        let data: Vec<f32> = outputs.iter().map(|o| o.get().borrow().data).collect();
+        assert_eq!(*activations.lock().unwrap(), vec![data[1], data[0], data[1], data[2]]);
+    }
+
     #[test]
     fn test_neuron_parameters() {
         let neuron = Neuron::new(2);
@@ -36,6 +158,18 @@ mod neuron_tests {
         assert_eq!(params.len(), 3); // 2 weights + 1 bias
     }
 
+    #[test]
+    fn test_zero_grad() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let input = vec![Value::new(1.0), Value::new(2.0)];
+        Value::back_propagate_accumulate(&mlp.forward(&input)[0]);
+        Value::back_propagate_accumulate(&mlp.forward(&input)[0]);
+        assert!(mlp.parameters().iter().any(|p| p.get().borrow().grad != 0.0));
+
+        mlp.zero_grad();
+        assert!(mlp.parameters().iter().all(|p| p.get().borrow().grad == 0.0));
+    }
+
     #[test]
     fn test_mlp_creation() {
         let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
@@ -53,6 +187,15 @@ mod neuron_tests {
         assert!(output[0].get().borrow().data >= -1.0 && output[0].get().borrow().data <= 1.0);
     }
 
+    #[test]
+    fn test_mlp_f64() {
+        let mlp = MultiLayerPerceptron::<f64>::random(2, vec![3, 1]);
+        let input = vec![Value::leaf(1.0_f64), Value::leaf(2.0_f64)];
+        let output = mlp.forward(&input);
+        assert_eq!(output.len(), 1);
+        assert!(output[0].get().borrow().data >= -1.0 && output[0].get().borrow().data <= 1.0);
+    }
+
     #[test]
     fn test_mlp_parameters() {
         let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
@@ -126,11 +269,11 @@ mod neuron_tests {
         // Define loss function
         fn loss(
             model: &MultiLayerPerceptron,
-            x: &Vec<Vec<RefValue>>,
-            y: &Vec<RefValue>,
+            x: &[Vec<RefValue>],
+            y: &[RefValue],
             batch_size: Option<usize>,
         ) -> (RefValue, f32) {
-            let (xb, yb) = if let Some(size) = batch_size {
+            let (xb, yb): (Vec<Vec<RefValue>>, Vec<RefValue>) = if let Some(size) = batch_size {
                 let mut indices: Vec<usize> = (0..x.len()).collect();
                 indices.shuffle(&mut rand::thread_rng());
                 let indices = &indices[0..size];
@@ -139,7 +282,7 @@ mod neuron_tests {
                     indices.iter().map(|&i| y[i].clone()).collect(),
                 )
             } else {
-                (x.clone(), y.clone())
+                (x.to_vec(), y.to_vec())
             };
 
             let scores: Vec<RefValue> = xb
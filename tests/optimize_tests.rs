@@ -0,0 +1,156 @@
+use micrograd_rs::op::Op;
+use micrograd_rs::optimize::optimize;
+use micrograd_rs::value::{RefValue, Value};
+use std::collections::HashSet;
+
+#[cfg(test)]
+mod optimize_tests {
+    use super::*;
+
+    #[allow(clippy::mutable_key_type)]
+    fn node_count(root: &RefValue) -> usize {
+        let mut topo = vec![];
+        let mut visited = HashSet::new();
+        Value::topological_sort(root, &mut topo, &mut visited);
+        topo.len()
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let x = Value::new(3.0);
+        let c = Value::exp(Value::new(0.0)) * 2.0 + Value::new(1.5);
+        let y = x.clone() * c;
+
+        let optimized = optimize(&y, std::slice::from_ref(&x));
+        assert_eq!(optimized.get().borrow().data, 10.5);
+        // x, the folded constant 3.5 and the product.
+        assert_eq!(node_count(&optimized), 3);
+        assert_eq!(optimized.get().borrow().children[1].get().borrow().data, 3.5);
+    }
+
+    #[test]
+    fn test_identity_removal() {
+        let x = Value::new(3.0);
+        let y = (x.clone() * 1.0 + 0.0) / 1.0;
+        let y = Value::pow(y, 1.0) - 0.0;
+
+        let optimized = optimize(&y, std::slice::from_ref(&x));
+        assert_eq!(optimized, x);
+    }
+
+    #[test]
+    fn test_negation() {
+        let x = Value::new(3.0);
+
+        let optimized = optimize(&(x.clone() * -1.0), std::slice::from_ref(&x));
+        assert_eq!(optimized.get().borrow().op(), Some(&Op::Neg));
+        assert_eq!(optimized.get().borrow().children, vec![x.clone()]);
+
+        let optimized = optimize(&(0.0 - x.clone()), std::slice::from_ref(&x));
+        assert_eq!(optimized.get().borrow().op(), Some(&Op::Neg));
+
+        let optimized = optimize(&-(-x.clone()), std::slice::from_ref(&x));
+        assert_eq!(optimized, x);
+    }
+
+    #[test]
+    fn test_sum_product_identities() {
+        let x = Value::new(3.0);
+        let y = Value::new(4.0);
+        let zero = Value::new(1.0) - 1.0;
+
+        let sum = Value::sum(vec![x.clone(), zero.clone(), y.clone(), Value::new(0.0)]);
+        let optimized = optimize(&sum, &[x.clone(), y.clone()]);
+        assert_eq!(optimized.get().borrow().children, vec![x.clone(), y.clone()]);
+
+        let product = Value::product(vec![Value::new(1.0), x.clone()]);
+        assert_eq!(optimize(&product, &[x.clone(), y.clone()]), x);
+    }
+
+    #[test]
+    fn test_common_subexpressions() {
+        let x = Value::new(0.5);
+        let w = Value::new(2.0);
+        let y = Value::tanh(x.clone() * w.clone()) + Value::tanh(w.clone() * x.clone());
+
+        let optimized = optimize(&y, &[x.clone(), w.clone()]);
+        // x, w, x * w, tanh and the sum.
+        assert_eq!(node_count(&y), 7);
+        assert_eq!(node_count(&optimized), 5);
+
+        let children = &optimized.get().borrow().children;
+        assert_eq!(children[0], children[1]);
+    }
+
+    #[test]
+    fn test_different_ops_not_merged() {
+        let x = Value::new(2.0);
+        let y = Value::pow(x.clone(), 2.0) + Value::pow(x.clone(), 3.0);
+
+        let optimized = optimize(&y, std::slice::from_ref(&x));
+        assert_eq!(node_count(&optimized), 4);
+        assert_eq!(optimized.get().borrow().data, 12.0);
+    }
+
+    #[test]
+    fn test_gradients_match() {
+        let a = Value::new(0.7);
+        let b = Value::new(-1.3);
+        let build = || {
+            let h = Value::tanh(a.clone() * 1.0 + b.clone() * (Value::new(2.0) * 0.5));
+            let g = Value::sigmoid(b.clone() * a.clone()) / (Value::exp(Value::new(0.0)) + 1.0);
+            (h.clone() * h - 0.0) * -1.0 + g + Value::sigmoid(a.clone() * b.clone()) * 1.0
+        };
+
+        let original = build();
+        Value::back_propagate(&original);
+        let expected: Vec<f32> = [&a, &b].iter().map(|v| v.get().borrow().grad).collect();
+
+        let optimized = optimize(&build(), &[a.clone(), b.clone()]);
+        assert!(node_count(&optimized) < node_count(&original));
+        Value::back_propagate(&optimized);
+
+        assert_eq!(optimized.get().borrow().data, original.get().borrow().data);
+        assert!((a.get().borrow().grad - expected[0]).abs() < 1e-6);
+        assert!((b.get().borrow().grad - expected[1]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_original_graph_unchanged() {
+        let x = Value::new(1.0);
+        let y = Value::tanh(x.clone() * 1.0).with_label("y");
+        let count = node_count(&y);
+
+        let optimized = optimize(&y, std::slice::from_ref(&x));
+        assert_eq!(node_count(&y), count);
+        assert_eq!(y.get().borrow().children[0].get().borrow().op(), Some(&Op::Mul));
+        assert_eq!(optimized.get().borrow().children, vec![x.clone()]);
+        assert_eq!(optimized.get().borrow().label(), Some("y"));
+    }
+
+    #[test]
+    fn test_hooks_kept() {
+        let x = Value::new(0.5);
+        let w = Value::new(2.0);
+        let build = || {
+            // Rebuilt as its child `x * 1` is removed.
+            let scaled = Value::tanh(x.clone() * 1.0);
+            scaled.register_hook(|grad: f32| Some(grad * 10.0));
+            // Would be merged with the other `x * w` without its hook.
+            let clipped = x.clone() * w.clone();
+            clipped.register_hook(|grad: f32| Some(grad.clamp(-0.1, 0.1)));
+            let unchanged = w.clone() * x.clone();
+            -(-scaled) + clipped + unchanged
+        };
+
+        let original = build();
+        Value::back_propagate(&original);
+        let expected: Vec<f32> = [&x, &w].iter().map(|v| v.get().borrow().grad).collect();
+
+        let optimized = optimize(&build(), &[x.clone(), w.clone()]);
+        Value::back_propagate(&optimized);
+
+        assert!((x.get().borrow().grad - expected[0]).abs() < 1e-6);
+        assert!((w.get().borrow().grad - expected[1]).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,155 @@
+use micrograd_rs::neuron::{MultiLayerPerceptron, NetworkParameters};
+use micrograd_rs::program::Program;
+use micrograd_rs::value::{RefValue, Value};
+
+#[cfg(test)]
+mod program_tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_placeholders() {
+        let x = Value::new(1.0);
+        let w = Value::new(3.0);
+        let y = Value::tanh(x.clone() * w.clone() + 0.5);
+
+        let mut program = Program::compile(&y, std::slice::from_ref(&x));
+        assert_eq!(program.output(), y.get().borrow().data);
+
+        for input in [-2.0_f32, 0.0, 0.25, 4.0] {
+            let expected = (input * 3.0 + 0.5).tanh();
+            assert!((program.forward(&[input]) - expected).abs() < 1e-6);
+        }
+        // The placeholder itself is left untouched.
+        assert_eq!(x.get().borrow().data, 1.0);
+    }
+
+    #[test]
+    fn test_back_propagate_matches_value() {
+        let x = Value::new(0.5);
+        let a = Value::new(-1.5);
+        let b = Value::new(2.0);
+        let build = |x: RefValue| Value::exp(x.clone() * a.clone()) / (b.clone() + x.clone() * x);
+
+        let mut program = Program::compile(&build(x.clone()), std::slice::from_ref(&x));
+        for input in [0.5, -0.3, 1.7] {
+            let output = program.forward(&[input]);
+            program.back_propagate();
+            let (a_grad, b_grad) = (a.get().borrow().grad, b.get().borrow().grad);
+            let x_grad = program.input_grads()[0];
+
+            let x = Value::new(input);
+            let y = build(x.clone());
+            Value::back_propagate(&y);
+            assert_eq!(output, y.get().borrow().data);
+            assert_eq!(x_grad, x.get().borrow().grad);
+            assert_eq!(a_grad, a.get().borrow().grad);
+            assert_eq!(b_grad, b.get().borrow().grad);
+        }
+    }
+
+    #[test]
+    fn test_bound_leaves_are_read_on_forward() {
+        let x = Value::new(2.0);
+        let w = Value::new(3.0);
+        let mut program = Program::compile(&(x.clone() * w.clone()), std::slice::from_ref(&x));
+        let len = program.len();
+
+        assert_eq!(program.forward(&[2.0]), 6.0);
+        w.get().borrow_mut().data = -1.0;
+        assert_eq!(program.forward(&[2.0]), -2.0);
+        assert_eq!(program.len(), len);
+    }
+
+    #[test]
+    fn test_unused_placeholder() {
+        let x = Value::new(2.0);
+        let unused = Value::new(5.0);
+        let mut program = Program::compile(&Value::pow(x.clone(), 2.0), &[x.clone(), unused]);
+
+        assert_eq!(program.forward(&[3.0, 100.0]), 9.0);
+        program.back_propagate();
+        assert_eq!(program.input_grads(), vec![6.0, 0.0]);
+    }
+
+    #[test]
+    fn test_shared_node() {
+        let x = Value::new(3.0);
+        let p = Value::new(1.0);
+        let h = x.clone() * p.clone();
+        let y = h.clone() * h.clone();
+
+        // The shared node is compiled once: x, p, h and y.
+        let mut program = Program::compile(&y, std::slice::from_ref(&x));
+        assert_eq!(program.len(), 4);
+        assert_eq!(program.forward(&[2.0]), 4.0);
+        program.back_propagate();
+        assert_eq!(program.input_grads(), vec![4.0]);
+        assert_eq!(p.get().borrow().grad, 8.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be leaves")]
+    fn test_non_leaf_input() {
+        let x = Value::new(2.0) + 1.0;
+        Program::compile(&Value::tanh(x.clone()), &[x]);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of inputs")]
+    fn test_wrong_number_of_inputs() {
+        let x = Value::new(2.0);
+        let mut program = Program::compile(&Value::tanh(x.clone()), std::slice::from_ref(&x));
+        program.forward(&[1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_train_matches_rebuilt_graph() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let reference = MultiLayerPerceptron::new(2, vec![3, 1]);
+        for (p, q) in mlp.parameters().iter().zip(reference.parameters()) {
+            q.get().borrow_mut().data = p.get().borrow().data;
+        }
+
+        let xs = vec![
+            vec![Value::new(0.0), Value::new(1.0)],
+            vec![Value::new(1.0), Value::new(-1.0)],
+        ];
+        let ys = vec![Value::new(1.0), Value::new(-1.0)];
+        mlp.train(0.1, 20, xs.clone(), ys.clone());
+
+        // Rebuild the loss from scratch on every iteration.
+        for _ in 0..20 {
+            let loss = xs
+                .iter()
+                .zip(ys.iter())
+                .map(|(x, y)| Value::pow(y.clone() - reference.forward(x)[0].clone(), 2.0))
+                .sum::<RefValue>()
+                / 2.0;
+            Value::back_propagate(&loss);
+            for p in reference.parameters() {
+                Value::backward(&p, 0.1);
+            }
+        }
+
+        for (p, q) in mlp.parameters().iter().zip(reference.parameters()) {
+            assert_eq!(p.get().borrow().data, q.get().borrow().data);
+        }
+    }
+
+    #[test]
+    fn test_grad_hooks() {
+        let x = Value::new(1.0);
+        let w = Value::new(3.0);
+        let h = x.clone() * w.clone();
+        h.register_hook(|grad: f32| Some(grad * 0.5));
+        w.register_hook(|grad: f32| Some(-grad));
+
+        let mut program = Program::compile(&Value::pow(h, 2.0), std::slice::from_ref(&x));
+        program.forward(&[2.0]);
+        program.back_propagate();
+
+        // d/dh = 2h = 12, halved by the hook on h, then reversed on w.
+        assert_eq!(program.input_grads(), vec![18.0]);
+        assert_eq!(w.get().borrow().grad, -12.0);
+    }
+}
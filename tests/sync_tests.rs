@@ -0,0 +1,179 @@
+#![cfg(feature = "sync")]
+
+use micrograd_rs::anomaly::detect_anomaly;
+use micrograd_rs::error::Error;
+use micrograd_rs::grad_mode::no_grad;
+use micrograd_rs::neuron::{MultiLayerPerceptron, NetworkParameters};
+use micrograd_rs::value::{RefValue, Value};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(test)]
+mod sync_tests {
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn batch() -> (Vec<Vec<RefValue>>, Vec<RefValue>) {
+        let xs = (0..16)
+            .map(|i| vec![Value::new(i as f32 / 16.0), Value::new(1.0 - i as f32 / 8.0)])
+            .collect();
+        let ys = (0..16)
+            .map(|i| Value::new(if i % 2 == 0 { 1.0 } else { -1.0 }))
+            .collect();
+        (xs, ys)
+    }
+
+    #[test]
+    fn test_types_are_send_sync() {
+        assert_send_sync::<RefValue>();
+        assert_send_sync::<MultiLayerPerceptron>();
+    }
+
+    #[test]
+    fn test_move_model_to_thread() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let expected = mlp.forward(&[Value::new(1.0), Value::new(2.0)])[0].get().borrow().data;
+
+        let output = std::thread::spawn(move || {
+            mlp.forward(&[Value::new(1.0), Value::new(2.0)])[0].get().borrow().data
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_par_forward() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let (xs, _) = batch();
+
+        let outputs = mlp.par_forward(&xs);
+
+        assert_eq!(outputs.len(), xs.len());
+        for (x, output) in xs.iter().zip(outputs) {
+            let expected = mlp.forward(x)[0].get().borrow().data;
+            assert_eq!(output[0].get().borrow().data, expected);
+        }
+    }
+
+    #[test]
+    fn test_par_forward_no_grad() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let (xs, _) = batch();
+
+        let outputs = no_grad(|| mlp.par_forward(&xs));
+
+        for output in outputs {
+            assert!(output[0].get().borrow().children.is_empty());
+        }
+        assert!(!mlp.par_forward(&xs)[0][0].get().borrow().children.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "anomaly detected: `dot` produced a NaN output")]
+    fn test_par_forward_anomaly() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let (mut xs, _) = batch();
+        xs[5][0] = Value::new(f32::NAN);
+
+        detect_anomaly(|| mlp.par_forward(&xs));
+    }
+
+    #[test]
+    fn test_par_back_propagate() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let (xs, ys) = batch();
+        let loss = |ypred: &[RefValue], y: &RefValue| Value::pow(y.clone() - ypred[0].clone(), 2.0);
+
+        let total = xs
+            .iter()
+            .zip(ys.iter())
+            .map(|(x, y)| loss(&mlp.forward(x), y))
+            .fold(Value::new(0.0), |acc, l| acc + l);
+        Value::back_propagate(&total);
+        let expected: Vec<f32> = mlp.parameters().iter().map(|p| p.get().borrow().grad).collect();
+
+        let total_loss = mlp.par_back_propagate(&xs, &ys, loss);
+
+        assert!((total_loss - total.get().borrow().data).abs() < 1e-4);
+        for (p, expected) in mlp.parameters().iter().zip(expected) {
+            assert!((p.get().borrow().grad - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of inputs: expected 16 but got 15")]
+    fn test_par_back_propagate_mismatched_targets() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let (xs, mut ys) = batch();
+        ys.pop();
+
+        mlp.par_back_propagate(&xs, &ys, |ypred, y| Value::pow(y.clone() - ypred[0].clone(), 2.0));
+    }
+
+    // Locks `value` on another thread for a while, returning once it is locked.
+    fn hold_lock(value: &RefValue, write: bool) -> thread::JoinHandle<()> {
+        let (locked, wait_locked) = mpsc::channel();
+        let value = value.clone();
+        let handle = thread::spawn(move || {
+            if write {
+                let _guard = value.get().borrow_mut();
+                locked.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            } else {
+                let _guard = value.get().borrow();
+                locked.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        wait_locked.recv().unwrap();
+        handle
+    }
+
+    #[test]
+    fn test_contended_lock() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let x = vec![Value::new(1.0), Value::new(2.0)];
+        let weight = mlp.parameters()[0].clone();
+
+        // The `try_` functions report a lock held by another thread, the others wait for it.
+        let holder = hold_lock(&weight, true);
+        assert_eq!(mlp.try_forward(&x).err(), Some(Error::BorrowConflict));
+        holder.join().unwrap();
+        let holder = hold_lock(&weight, true);
+        let y = mlp.forward(&x)[0].clone();
+        holder.join().unwrap();
+
+        let holder = hold_lock(&x[0], false);
+        assert_eq!(Value::try_back_propagate(&y), Err(Error::BorrowConflict));
+        holder.join().unwrap();
+        let holder = hold_lock(&x[0], false);
+        Value::back_propagate(&y);
+        holder.join().unwrap();
+        assert_ne!(x[0].get().borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn test_par_back_propagate_hooks() {
+        let mlp = MultiLayerPerceptron::new(2, vec![3, 1]);
+        let (xs, ys) = batch();
+        let loss = |ypred: &[RefValue], y: &RefValue| Value::pow(y.clone() - ypred[0].clone(), 2.0);
+        let weight = mlp.parameters()[0].clone();
+        weight.register_hook(|grad: f32| Some(grad.clamp(-0.01, 0.01)));
+
+        let total = xs
+            .iter()
+            .zip(ys.iter())
+            .map(|(x, y)| loss(&mlp.forward(x), y))
+            .sum::<RefValue>();
+        Value::back_propagate(&total);
+        let expected = weight.get().borrow().grad;
+
+        mlp.par_back_propagate(&xs, &ys, loss);
+        assert!((weight.get().borrow().grad - expected).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,148 @@
+use micrograd_rs::op::{CustomOp, Op};
+use micrograd_rs::tape::Tape;
+use micrograd_rs::value::Value;
+
+// An operation which forgets to return its local gradients.
+#[derive(Debug)]
+struct NoGrads;
+
+impl CustomOp for NoGrads {
+    fn name(&self) -> &str {
+        "no_grads"
+    }
+
+    fn forward(&self, inputs: &[f32]) -> f32 {
+        inputs[0]
+    }
+
+    fn local_grads(&self, _inputs: &[f32], _output: f32) -> Vec<f32> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tape_tests {
+
+    use super::*;
+
+    #[test]
+    fn test_operators() {
+        let tape = Tape::new();
+        let a = tape.var(2.0);
+        let b = tape.var(3.0);
+        let c = tape.var(4.0);
+        let d = tape.var(5.0);
+
+        // (2 + 3) * (4 - 5) / 2
+        let x = (a + b) * (c - d) / 2.0;
+
+        assert_eq!(x.data(), -2.5);
+    }
+
+    #[test]
+    fn test_scalar_operations() {
+        let tape = Tape::new();
+        let a = tape.var(2.0);
+        let x = 3 + a * 4.0 - 2;
+
+        assert_eq!(x.data(), 9.0);
+    }
+
+    #[test]
+    fn test_backward() {
+        let tape = Tape::new();
+        let a = tape.var(2.0);
+        let b = tape.var(3.0);
+        let c = a * b;
+        tape.back_propagate(c);
+
+        assert_eq!(a.grad(), 3.0);
+        assert_eq!(b.grad(), 2.0);
+        assert_eq!(c.op(), Some(Op::Mul));
+    }
+
+    #[test]
+    fn test_sub_div_backward() {
+        let tape = Tape::new();
+        let a = tape.var(6.0);
+        let b = tape.var(4.0);
+        let c = a / b - b;
+        tape.back_propagate(c);
+
+        // a / b and the subtraction, on top of the two leaves.
+        assert_eq!(tape.len(), 4);
+        assert_eq!(c.op(), Some(Op::Sub));
+        assert_eq!(a.grad(), 0.25);
+        assert_eq!(b.grad(), -1.375);
+    }
+
+    #[test]
+    fn test_matches_value() {
+        let tape = Tape::new();
+        let (a, b, c) = (tape.var(0.5), tape.var(-0.3), tape.var(0.2));
+        let x = (a * b + c).tanh() + a.exp() - b.pow(2.0) / c + (a - c).relu();
+        tape.back_propagate(x);
+
+        let (va, vb, vc) = (Value::new(0.5), Value::new(-0.3), Value::new(0.2));
+        let y = Value::tanh(va.clone() * vb.clone() + vc.clone()) + Value::exp(va.clone())
+            - Value::pow(vb.clone(), 2.0) / vc.clone()
+            + Value::relu(va.clone() - vc.clone());
+        Value::back_propagate(&y);
+
+        assert_eq!(x.data(), y.get().borrow().data);
+        assert_eq!(a.grad(), va.get().borrow().grad);
+        assert_eq!(b.grad(), vb.get().borrow().grad);
+        assert_eq!(c.grad(), vc.get().borrow().grad);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let tape = Tape::new();
+        let w = tape.var(1.0);
+        let checkpoint = tape.len();
+
+        for _ in 0..10 {
+            let loss = (w * 2.0 - 3.0).pow(2.0);
+            tape.back_propagate(loss);
+            w.backward(0.1);
+            tape.truncate(checkpoint);
+        }
+
+        assert_eq!(tape.len(), checkpoint);
+        assert!((w.data() - 1.5).abs() < 1e-2);
+    }
+
+    #[test]
+    #[should_panic(expected = "forgotten by Tape::truncate")]
+    fn test_truncated_var() {
+        let tape = Tape::new();
+        let w = tape.var(1.0);
+        let stale = w * 2.0;
+        tape.truncate(1);
+
+        // `fresh` is recorded at the index `stale` used to refer to.
+        let fresh = w + 5.0;
+        assert_eq!(fresh.index(), stale.index());
+        assert_eq!(fresh.data(), 6.0);
+        stale.data();
+    }
+
+    #[test]
+    #[should_panic(expected = "`+` can't be applied to 1 children")]
+    fn test_apply_arity() {
+        let tape = Tape::new();
+        let a = tape.var(2.0);
+
+        tape.apply(Op::Add, &[a]);
+    }
+
+    #[test]
+    #[should_panic(expected = "`no_grads` returned 0 local gradients for 1 children")]
+    fn test_local_grads() {
+        let tape = Tape::new();
+        let a = tape.var(2.0);
+        let y = tape.custom(NoGrads, &[a]);
+
+        tape.back_propagate(y);
+    }
+}
@@ -1,10 +1,45 @@
-use micrograd_rs::value::{Value};
+use micrograd_rs::anomaly::detect_anomaly;
+use micrograd_rs::derivatives::jacobian;
+use micrograd_rs::error::Error;
+use micrograd_rs::grad_mode::{is_grad_enabled, no_grad, NoGradGuard};
+use micrograd_rs::neuron::MultiLayerPerceptron;
+use micrograd_rs::op::{CustomOp, Op};
+use micrograd_rs::value::{RefValue, Value};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+struct Softsign;
+
+impl CustomOp for Softsign {
+    fn name(&self) -> &str {
+        "softsign"
+    }
+
+    fn forward(&self, inputs: &[f32]) -> f32 {
+        inputs[0] / (1.0 + inputs[0].abs())
+    }
+
+    fn local_grads(&self, inputs: &[f32], _output: f32) -> Vec<f32> {
+        vec![1.0 / (1.0 + inputs[0].abs()).powi(2)]
+    }
+}
 
 #[cfg(test)]
 mod value_tests {
 
     use super::*;
 
+    #[test]
+    fn test_new_defaults_to_f32() {
+        // `new` is f32 even without annotations, `leaf` creates values of any float type.
+        let x = Value::new(1.0);
+        let data: f32 = x.get().borrow().data;
+        assert_eq!(data, 1.0);
+
+        let y = Value::leaf(1.0_f64) * 2.0;
+        assert_eq!(y.get().borrow().data, 2.0_f64);
+    }
+
     #[test]
     fn test_mul() {
         // Your test code here
@@ -186,4 +221,847 @@ mod value_tests {
         Value::back_propagate(&z);
         assert_eq!(c.get().borrow().grad, 0.0);
     }
+
+    #[test]
+    fn test_op_recorded() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let x = Value::pow(a.clone() * b, 2.0);
+
+        assert_eq!(a.get().borrow().op(), None);
+        assert_eq!(x.get().borrow().op(), Some(&Op::Pow(2.0)));
+        assert_eq!(x.get().borrow().children[0].get().borrow().op(), Some(&Op::Mul));
+    }
+
+    #[test]
+    fn test_pow_backward() {
+        let a = Value::new(3.0);
+        let x = Value::pow(a.clone(), 2.0);
+        Value::back_propagate(&x);
+
+        assert_eq!(a.get().borrow().grad, 6.0);
+    }
+
+    #[test]
+    fn test_apply() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let x = a.clone() * b.clone();
+
+        let y = Value::apply(x.get().borrow().op().unwrap().clone(), vec![b.clone(), b.clone()]);
+        assert_eq!(y.get().borrow().data, 9.0);
+        let dot = Value::apply(Op::Dot, vec![a.clone(), b.clone(), b, a]);
+        assert_eq!(dot.get().borrow().data, 12.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "`+` can't be applied to 1 children")]
+    fn test_apply_wrong_arity() {
+        Value::apply(Op::Add, vec![Value::new(1.0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "`dot` can't be applied to 3 children")]
+    fn test_apply_odd_dot() {
+        Value::apply(Op::Dot, vec![Value::new(1.0), Value::new(2.0), Value::new(3.0)]);
+    }
+
+    #[test]
+    fn test_custom_op() {
+        let a = Value::new(1.0);
+        let b = Value::new(2.0);
+        let x = Value::custom(Softsign, vec![a.clone()]) * b.clone();
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, 1.0);
+        assert_eq!(a.get().borrow().grad, 0.5);
+        assert_eq!(b.get().borrow().grad, 0.5);
+
+        let softsign = x.get().borrow().children[0].clone();
+        assert!(softsign.to_string().contains("op=softsign"));
+        assert!(Value::generate_mermaid_graph(&x).contains("op=softsign"));
+    }
+
+    #[test]
+    fn test_deep_graph() {
+        let depth = 100_000;
+        let a = Value::new(1.0);
+        let mut x = a.clone();
+        for _ in 0..depth {
+            x = x + 1.0;
+        }
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, (depth + 1) as f32);
+        assert_eq!(a.get().borrow().grad, 1.0);
+
+        let graph = Value::generate_mermaid_graph(&x);
+        assert_eq!(graph.lines().filter(|l| l.contains(" : ")).count(), 2 * depth + 1);
+    }
+
+    #[test]
+    fn test_f64_values() {
+        let a = Value::leaf(0.3_f64);
+        let b = Value::leaf(3.0_f64);
+        let x = Value::tanh(a.clone()) / b.clone();
+        Value::back_propagate(&x);
+
+        let t = 0.3_f64.tanh();
+        assert!((x.get().borrow().data - t / 3.0).abs() < 1e-15);
+        assert!((a.get().borrow().grad - (1.0 - t * t) / 3.0).abs() < 1e-15);
+        assert!((b.get().borrow().grad + t / 9.0).abs() < 1e-15);
+    }
+
+    #[test]
+    fn test_second_derivative() {
+        let x = Value::new(2.0);
+        let y = Value::pow(x.clone(), 3.0);
+
+        let dx = Value::gradients(&y, std::slice::from_ref(&x)).remove(0);
+        assert_eq!(dx.get().borrow().data, 12.0);
+        assert_eq!(x.get().borrow().grad, 12.0);
+
+        Value::back_propagate(&dx);
+        assert_eq!(x.get().borrow().grad, 12.0);
+    }
+
+    #[test]
+    fn test_second_derivative_tanh() {
+        let x = Value::new(0.5);
+        let y = Value::tanh(x.clone());
+
+        let dx = Value::gradients(&y, std::slice::from_ref(&x)).remove(0);
+        let ddx = Value::gradients(&dx, std::slice::from_ref(&x)).remove(0);
+
+        let t = 0.5_f32.tanh();
+        assert!((dx.get().borrow().data - (1.0 - t * t)).abs() < 1e-6);
+        assert!((ddx.get().borrow().data + 2.0 * t * (1.0 - t * t)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mixed_second_derivative() {
+        let x = Value::new(3.0);
+        let y = Value::new(4.0);
+        let f = x.clone() * y.clone() * y.clone();
+
+        let grads = Value::gradients(&f, &[x.clone(), y.clone()]);
+        assert_eq!(grads[0].get().borrow().data, 16.0);
+        assert_eq!(grads[1].get().borrow().data, 24.0);
+
+        // d/dy (df/dx) = 2y
+        Value::back_propagate(&grads[0]);
+        assert_eq!(y.get().borrow().grad, 8.0);
+    }
+
+    #[test]
+    fn test_no_grad() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+
+        let x = no_grad(|| Value::tanh(a.clone() * b.clone()));
+        assert!((x.get().borrow().data - 6.0_f32.tanh()).abs() < 1e-6);
+        assert!(x.get().borrow().children.is_empty());
+        assert_eq!(x.get().borrow().op(), None);
+
+        let y = a * b;
+        assert_eq!(y.get().borrow().children.len(), 2);
+    }
+
+    #[test]
+    fn test_no_grad_guard_nesting() {
+        {
+            let _outer = NoGradGuard::new();
+            {
+                let _inner = NoGradGuard::new();
+                assert!(!is_grad_enabled());
+            }
+            assert!(!is_grad_enabled());
+        }
+        assert!(is_grad_enabled());
+    }
+
+    #[test]
+    fn test_detach() {
+        let a = Value::new(2.0);
+        let b = a.clone() * 3.0;
+        let c = b.detach();
+        let x = c.clone() * a.clone();
+        Value::back_propagate(&x);
+
+        assert_eq!(c.get().borrow().data, 6.0);
+        assert!(c.get().borrow().children.is_empty());
+        // The detached value is treated as a constant.
+        assert_eq!(a.get().borrow().grad, 6.0);
+        assert_eq!(c.get().borrow().grad, 2.0);
+    }
+
+    #[test]
+    fn test_back_propagate_accumulate() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+
+        Value::back_propagate_accumulate(&(a.clone() * b.clone()));
+        Value::back_propagate_accumulate(&(a.clone() + b.clone()));
+        assert_eq!(a.get().borrow().grad, 4.0);
+        assert_eq!(b.get().borrow().grad, 3.0);
+
+        // A regular back propagation replaces the accumulated gradients.
+        Value::back_propagate(&(a.clone() * b.clone()));
+        assert_eq!(a.get().borrow().grad, 3.0);
+        assert_eq!(b.get().borrow().grad, 2.0);
+    }
+
+    #[test]
+    fn test_back_propagate_accumulate_shared_graph() {
+        let a = Value::new(2.0);
+        let x = a.clone() * a.clone();
+        let y = x.clone() + 1.0;
+
+        // `x` is interior to both passes so its gradient must not leak into the second one.
+        Value::back_propagate_accumulate(&y);
+        Value::back_propagate_accumulate(&y);
+        assert_eq!(a.get().borrow().grad, 8.0);
+        assert_eq!(x.get().borrow().grad, 1.0);
+    }
+
+    #[test]
+    fn test_log_sqrt() {
+        let a = Value::new(4.0);
+        let x = Value::log(a.clone());
+        Value::back_propagate(&x);
+        assert!((x.get().borrow().data - 4.0_f32.ln()).abs() < 1e-6);
+        assert_eq!(a.get().borrow().grad, 0.25);
+
+        let b = Value::new(4.0);
+        let y = Value::sqrt(b.clone());
+        Value::back_propagate(&y);
+        assert_eq!(y.get().borrow().data, 2.0);
+        assert_eq!(b.get().borrow().grad, 0.25);
+    }
+
+    #[test]
+    fn test_abs_neg() {
+        let a = Value::new(-3.0);
+        let x = Value::abs(a.clone());
+        Value::back_propagate(&x);
+        assert_eq!(x.get().borrow().data, 3.0);
+        assert_eq!(a.get().borrow().grad, -1.0);
+
+        let b = Value::new(2.0);
+        let y = -b.clone();
+        Value::back_propagate(&y);
+        assert_eq!(y.get().borrow().data, -2.0);
+        assert_eq!(b.get().borrow().grad, -1.0);
+    }
+
+    #[test]
+    fn test_sin_cos() {
+        let a = Value::new(0.5);
+        let x = Value::sin(a.clone()) + Value::cos(a.clone());
+        Value::back_propagate(&x);
+
+        assert!((x.get().borrow().data - (0.5_f32.sin() + 0.5_f32.cos())).abs() < 1e-6);
+        assert!((a.get().borrow().grad - (0.5_f32.cos() - 0.5_f32.sin())).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sigmoid_softplus() {
+        let a = Value::new(0.0);
+        let x = Value::sigmoid(a.clone());
+        Value::back_propagate(&x);
+        assert_eq!(x.get().borrow().data, 0.5);
+        assert_eq!(a.get().borrow().grad, 0.25);
+
+        let b = Value::new(0.0);
+        let y = Value::softplus(b.clone());
+        Value::back_propagate(&y);
+        assert!((y.get().borrow().data - 2.0_f32.ln()).abs() < 1e-6);
+        assert_eq!(b.get().borrow().grad, 0.5);
+
+        // Large inputs must not overflow.
+        assert_eq!(Value::sigmoid(Value::new(-1000.0)).get().borrow().data, 0.0);
+        assert_eq!(Value::softplus(Value::new(1000.0)).get().borrow().data, 1000.0);
+    }
+
+    #[test]
+    fn test_leaky_relu_elu() {
+        let a = Value::new(-2.0);
+        let x = Value::leaky_relu(a.clone(), 0.1);
+        Value::back_propagate(&x);
+        assert_eq!(x.get().borrow().data, -0.2);
+        assert_eq!(a.get().borrow().grad, 0.1);
+
+        let b = Value::new(-1.0);
+        let y = Value::elu(b.clone(), 1.0);
+        Value::back_propagate(&y);
+        assert!((y.get().borrow().data - ((-1.0_f32).exp() - 1.0)).abs() < 1e-6);
+        assert!((b.get().borrow().grad - (-1.0_f32).exp()).abs() < 1e-6);
+
+        let c = Value::new(2.0);
+        let z = Value::elu(c.clone(), 1.0);
+        Value::back_propagate(&z);
+        assert_eq!(z.get().borrow().data, 2.0);
+        assert_eq!(c.get().borrow().grad, 1.0);
+    }
+
+    #[test]
+    fn test_gelu_silu() {
+        let a = Value::new(1.0_f32);
+        let x = Value::gelu(a.clone());
+        Value::back_propagate(&x);
+        assert!((x.get().borrow().data - 0.841192).abs() < 1e-5);
+        assert!((a.get().borrow().grad - 1.082964).abs() < 1e-5);
+
+        let b = Value::new(1.0);
+        let y = Value::silu(b.clone());
+        Value::back_propagate(&y);
+        let s = 1.0 / (1.0 + (-1.0_f32).exp());
+        assert!((y.get().borrow().data - s).abs() < 1e-6);
+        assert!((b.get().borrow().grad - s * (2.0 - s)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_clamp() {
+        let a = Value::new(5.0);
+        let x = Value::clamp(a.clone(), -1.0, 1.0);
+        Value::back_propagate(&x);
+        assert_eq!(x.get().borrow().data, 1.0);
+        assert_eq!(a.get().borrow().grad, 0.0);
+
+        let b = Value::new(0.5);
+        let y = Value::clamp(b.clone(), -1.0, 1.0);
+        Value::back_propagate(&y);
+        assert_eq!(y.get().borrow().data, 0.5);
+        assert_eq!(b.get().borrow().grad, 1.0);
+    }
+
+    #[test]
+    fn test_min_max() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let x = Value::min(a.clone(), b.clone()) * 2.0 + Value::max(a.clone(), b.clone()) * 5.0;
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, 19.0);
+        assert_eq!(a.get().borrow().grad, 2.0);
+        assert_eq!(b.get().borrow().grad, 5.0);
+    }
+
+    #[test]
+    fn test_pow_value() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let x = Value::pow_value(a.clone(), b.clone());
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, 8.0);
+        assert_eq!(a.get().borrow().grad, 12.0);
+        assert!((b.get().borrow().grad - 8.0 * 2.0_f32.ln()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_pow_value_zero_base() {
+        let a = Value::new(0.0);
+        let b = Value::new(2.0);
+        let x = Value::pow_value(a.clone(), b.clone());
+
+        // A zero output gives the exponent a zero gradient instead of 0 * ln(0), which is NaN.
+        assert_eq!(detect_anomaly(|| Value::try_back_propagate(&x)), Ok(()));
+        assert_eq!(a.get().borrow().grad, 0.0);
+        assert_eq!(b.get().borrow().grad, 0.0);
+
+        let db = Value::gradients(&x, std::slice::from_ref(&b)).remove(0);
+        assert_eq!(db.get().borrow().data, 0.0);
+    }
+
+    #[test]
+    fn test_pow_value_negative_base() {
+        let a = Value::new(-2.0);
+        let b = Value::new(3.0);
+        let x = Value::pow_value(a.clone(), b.clone());
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, -8.0);
+        assert_eq!(a.get().borrow().grad, 12.0);
+        // Like in PyTorch the exponent has no real derivative for a negative base.
+        assert!(b.get().borrow().grad.is_nan());
+    }
+
+    #[test]
+    fn test_second_derivative_sin() {
+        let x = Value::new(0.5);
+        let y = Value::sin(x.clone());
+
+        let dx = Value::gradients(&y, std::slice::from_ref(&x)).remove(0);
+        let ddx = Value::gradients(&dx, std::slice::from_ref(&x)).remove(0);
+
+        assert!((dx.get().borrow().data - 0.5_f32.cos()).abs() < 1e-6);
+        assert!((ddx.get().borrow().data + 0.5_f32.sin()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sum() {
+        let values: Vec<RefValue> = (1..=4).map(|i| Value::new(i as f32)).collect();
+        let x = Value::sum(values.clone());
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, 10.0);
+        assert_eq!(x.get().borrow().children.len(), 4);
+        assert!(values.iter().all(|v| v.get().borrow().grad == 1.0));
+
+        let empty: RefValue = Value::sum(vec![]);
+        assert_eq!(empty.get().borrow().data, 0.0);
+    }
+
+    #[test]
+    fn test_product() {
+        let a = Value::new(2.0);
+        let b = Value::new(0.0);
+        let c = Value::new(5.0);
+        let x = Value::product(vec![a.clone(), b.clone(), c.clone()]);
+        Value::back_propagate(&x);
+
+        // The gradients are exact even with a zero among the values.
+        assert_eq!(x.get().borrow().data, 0.0);
+        assert_eq!(a.get().borrow().grad, 0.0);
+        assert_eq!(b.get().borrow().grad, 10.0);
+        assert_eq!(c.get().borrow().grad, 0.0);
+
+        let empty: RefValue = Value::product(vec![]);
+        assert_eq!(empty.get().borrow().data, 1.0);
+    }
+
+    #[test]
+    fn test_dot() {
+        let xs = vec![Value::new(1.0), Value::new(2.0), Value::new(3.0)];
+        let ws = vec![Value::new(4.0), Value::new(-5.0), Value::new(6.0)];
+        let x = Value::dot(&xs, &ws);
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, 12.0);
+        assert_eq!(x.get().borrow().children.len(), 6);
+        assert_eq!(xs[1].get().borrow().grad, -5.0);
+        assert_eq!(ws[2].get().borrow().grad, 3.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "different lengths")]
+    fn test_dot_length_mismatch() {
+        Value::dot(&[Value::new(1.0)], &[Value::new(1.0), Value::new(2.0)]);
+    }
+
+    #[test]
+    fn test_iter_sum_product() {
+        let values = [Value::new(2.0), Value::new(3.0), Value::new(4.0)];
+
+        let sum: RefValue = values.iter().sum();
+        assert_eq!(sum.get().borrow().data, 9.0);
+        assert_eq!(sum.get().borrow().op(), Some(&Op::Sum));
+
+        let product: RefValue = values.iter().cloned().product();
+        Value::back_propagate(&product);
+        assert_eq!(product.get().borrow().data, 24.0);
+        assert_eq!(values[0].get().borrow().grad, 12.0);
+    }
+
+    #[test]
+    fn test_second_derivative_product() {
+        let x = Value::new(3.0);
+        let y = Value::new(2.0);
+        let z = Value::product(vec![x.clone(), x.clone(), y.clone()]);
+
+        // d/dx (x^2 * y) = 2xy, d2/dx2 = 2y, d2/dxdy = 2x
+        let dx = Value::gradients(&z, std::slice::from_ref(&x)).remove(0);
+        assert_eq!(dx.get().borrow().data, 12.0);
+
+        let second = Value::gradients(&dx, &[x.clone(), y.clone()]);
+        assert_eq!(second[0].get().borrow().data, 4.0);
+        assert_eq!(second[1].get().borrow().data, 6.0);
+    }
+
+    #[test]
+    fn test_sum_deep_graph() {
+        let values: Vec<RefValue> = (0..100_000).map(|_| Value::new(1.0)).collect();
+        let x: RefValue = values.iter().sum();
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().data, 100_000.0);
+        assert_eq!(values[99_999].get().borrow().grad, 1.0);
+    }
+
+    #[test]
+    fn test_sub_backward() {
+        let a = Value::new(5.0);
+        let b = Value::new(2.0);
+        let x = a.clone() - b.clone();
+        Value::back_propagate(&x);
+
+        // A single node, without an intermediate multiplication by -1.
+        assert_eq!(x.get().borrow().op(), Some(&Op::Sub));
+        assert_eq!(x.get().borrow().children, vec![a.clone(), b.clone()]);
+        assert_eq!(a.get().borrow().grad, 1.0);
+        assert_eq!(b.get().borrow().grad, -1.0);
+    }
+
+    #[test]
+    fn test_div_backward() {
+        let a = Value::new(6.0);
+        let b = Value::new(4.0);
+        let x = a.clone() / b.clone();
+        Value::back_propagate(&x);
+
+        assert_eq!(x.get().borrow().op(), Some(&Op::Div));
+        assert_eq!(x.get().borrow().children, vec![a.clone(), b.clone()]);
+        assert_eq!(a.get().borrow().grad, 0.25);
+        assert_eq!(b.get().borrow().grad, -0.375);
+    }
+
+    #[test]
+    fn test_scalar_lhs_sub_div() {
+        let a = Value::new(4.0_f32);
+        let x = 2.0 - a.clone();
+        let y = 2 / a.clone();
+
+        assert_eq!(x.get().borrow().data, -2.0);
+        assert_eq!(y.get().borrow().data, 0.5);
+        assert_eq!(y.get().borrow().op(), Some(&Op::Div));
+    }
+
+    #[test]
+    fn test_second_derivative_div() {
+        let x = Value::new(2.0_f32);
+        let y = Value::new(3.0);
+        let z = x.clone() / y.clone();
+
+        // d/dy (x / y) = -x / y^2, d2/dy2 = 2x / y^3, d2/dydx = -1 / y^2
+        let dy = Value::gradients(&z, std::slice::from_ref(&y)).remove(0);
+        assert!((dy.get().borrow().data + 2.0 / 9.0).abs() < 1e-6);
+
+        let second = Value::gradients(&dy, &[y.clone(), x.clone()]);
+        assert!((second[0].get().borrow().data - 4.0 / 27.0).abs() < 1e-6);
+        assert!((second[1].get().borrow().data + 1.0 / 9.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_logsumexp() {
+        let xs = vec![Value::new(1.0_f32), Value::new(2.0), Value::new(3.0)];
+        let x = Value::logsumexp(&xs);
+        Value::back_propagate(&x);
+
+        let expected = (1.0_f32.exp() + 2.0_f32.exp() + 3.0_f32.exp()).ln();
+        assert!((x.get().borrow().data - expected).abs() < 1e-6);
+        // The gradient of log-sum-exp is the softmax.
+        for x in &xs {
+            let softmax = (x.get().borrow().data - expected).exp();
+            assert!((x.get().borrow().grad - softmax).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_softmax() {
+        let xs = vec![Value::new(1.0_f32), Value::new(2.0), Value::new(3.0)];
+        let probs = Value::softmax(&xs);
+        Value::back_propagate(&probs[2]);
+
+        let total: f32 = probs.iter().map(|p| p.get().borrow().data).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+
+        // d s_k / d x_i = s_k * (1[i == k] - s_i)
+        let s: Vec<f32> = probs.iter().map(|p| p.get().borrow().data).collect();
+        assert!((xs[0].get().borrow().grad + s[2] * s[0]).abs() < 1e-6);
+        assert!((xs[2].get().borrow().grad - s[2] * (1.0 - s[2])).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let xs = vec![Value::new(0.5_f32), Value::new(-1.0), Value::new(2.0)];
+        let log_probs = Value::log_softmax(&xs);
+        Value::back_propagate(&log_probs[0]);
+
+        let lse = (0.5_f32.exp() + (-1.0_f32).exp() + 2.0_f32.exp()).ln();
+        assert!((log_probs[0].get().borrow().data - (0.5 - lse)).abs() < 1e-6);
+        assert!((xs[0].get().borrow().grad - (1.0 - (0.5 - lse).exp())).abs() < 1e-6);
+        assert!((xs[1].get().borrow().grad + (-1.0 - lse).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_softmax_large_logits() {
+        let xs = vec![Value::new(1000.0_f32), Value::new(1001.0), Value::new(-1000.0)];
+        let lse = Value::logsumexp(&xs);
+        let probs = Value::softmax(&xs);
+        let log_probs = Value::log_softmax(&xs);
+        Value::back_propagate(&log_probs[1]);
+
+        assert!((lse.get().borrow().data - (1001.0 + (-1.0_f32).exp().ln_1p())).abs() < 1e-3);
+        assert!(probs.iter().all(|p| p.get().borrow().data.is_finite()));
+        assert!((probs[0].get().borrow().data - 1.0 / (1.0 + 1.0_f32.exp())).abs() < 1e-4);
+        assert_eq!(probs[2].get().borrow().data, 0.0);
+        assert!(xs.iter().all(|x| x.get().borrow().grad.is_finite()));
+    }
+
+    #[test]
+    fn test_logsumexp_infinite() {
+        let xs = vec![Value::new(f32::NEG_INFINITY), Value::new(f32::NEG_INFINITY)];
+        let x = Value::logsumexp(&xs);
+        assert_eq!(detect_anomaly(|| Value::try_back_propagate(&x)), Ok(()));
+
+        assert_eq!(x.get().borrow().data, f32::NEG_INFINITY);
+        for x in &xs {
+            assert_eq!(x.get().borrow().grad, 0.5);
+        }
+
+        let xs = vec![Value::new(1.0), Value::new(f32::INFINITY)];
+        let x = Value::logsumexp(&xs);
+        let grads = Value::gradients(&x, &xs);
+        assert_eq!(grads[0].get().borrow().data, 0.0);
+        assert_eq!(grads[1].get().borrow().data, 1.0);
+    }
+
+    #[test]
+    fn test_second_derivative_logsumexp() {
+        let x = Value::new(0.3_f32);
+        let y = Value::new(-0.4);
+        let lse = Value::logsumexp(&[x.clone(), y.clone()]);
+
+        // d/dx = s, d2/dx2 = s * (1 - s) where s is the softmax of x.
+        let dx = Value::gradients(&lse, std::slice::from_ref(&x)).remove(0);
+        let ddx = Value::gradients(&dx, std::slice::from_ref(&x)).remove(0);
+
+        let s = 1.0 / (1.0 + (-0.7_f32).exp());
+        assert!((dx.get().borrow().data - s).abs() < 1e-6);
+        assert!((ddx.get().borrow().data - s * (1.0 - s)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_softmax_is_fused() {
+        let xs = vec![Value::new(1.0), Value::new(2.0), Value::new(3.0)];
+        let probs = Value::softmax(&xs);
+        let log_probs = Value::log_softmax(&xs);
+
+        assert_eq!(probs[1].get().borrow().op(), Some(&Op::Softmax(1)));
+        assert_eq!(log_probs[2].get().borrow().op(), Some(&Op::LogSoftmax(2)));
+        for output in probs.iter().chain(&log_probs) {
+            assert_eq!(output.get().borrow().children, xs);
+        }
+    }
+
+    #[test]
+    fn test_second_derivative_softmax() {
+        let x = Value::new(0.3_f32);
+        let y = Value::new(-0.4);
+        let xs = [x.clone(), y.clone()];
+        // With two inputs the first entry of the softmax is sigmoid(x - y).
+        let s = 1.0 / (1.0 + (-0.7_f32).exp());
+
+        let p = Value::softmax(&xs).remove(0);
+        let dx = Value::gradients(&p, std::slice::from_ref(&x)).remove(0);
+        let ddx = Value::gradients(&dx, std::slice::from_ref(&x)).remove(0);
+        assert!((dx.get().borrow().data - s * (1.0 - s)).abs() < 1e-6);
+        assert!((ddx.get().borrow().data - s * (1.0 - s) * (1.0 - 2.0 * s)).abs() < 1e-6);
+
+        let log_p = Value::log_softmax(&xs).remove(0);
+        let dx = Value::gradients(&log_p, std::slice::from_ref(&x)).remove(0);
+        let ddx = Value::gradients(&dx, std::slice::from_ref(&x)).remove(0);
+        assert!((dx.get().borrow().data - (1.0 - s)).abs() < 1e-6);
+        assert!((ddx.get().borrow().data + s * (1.0 - s)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mermaid_graph_shared_node() {
+        let p = Value::new(3.0);
+        let x = p.clone() * p.clone();
+        let graph = Value::generate_mermaid_graph(&x);
+
+        // The shared value is listed once with an edge for each use.
+        assert_eq!(graph.lines().filter(|l| l.contains(" : ")).count(), 2);
+        assert_eq!(graph.lines().filter(|l| *l == "s1 --> s2").count(), 2);
+    }
+
+    #[test]
+    fn test_dot_graph() {
+        let a = Value::new(2.0);
+        a.get().borrow_mut().set_label("a");
+        let b = Value::new(-3.0);
+        let x = Value::tanh(a.clone() * b.clone() + a.clone());
+        Value::back_propagate(&x);
+
+        let graph = Value::generate_dot_graph(&x, false);
+        assert!(graph.starts_with("digraph {"));
+        assert!(graph.trim_end().ends_with('}'));
+        assert_eq!(graph.matches("[label=").count(), 5);
+        assert_eq!(graph.matches(" -> ").count(), 5);
+        assert!(graph.contains("{ a | data 2.0000 | grad "));
+        assert!(graph.contains("{ tanh | data "));
+        assert!(!graph.contains("fillcolor"));
+    }
+
+    #[test]
+    fn test_dot_graph_highlight_grad() {
+        let a = Value::new(1.0);
+        let b = Value::new(4.0);
+        let x = a.clone() * b.clone();
+        Value::back_propagate(&x);
+
+        let graph = Value::generate_dot_graph(&x, true);
+        // a has the largest gradient, b (which is a.data) and x a quarter of it.
+        let highlighted = |node: &str, color: &str| {
+            graph.contains(&format!("{}\", style=filled, fillcolor=\"{}\"", node, color))
+        };
+        assert!(highlighted("{ data 1.0000 | grad 4.0000 }", "#ff0000ff"));
+        assert!(highlighted("{ data 4.0000 | grad 1.0000 }", "#ff000040"));
+        assert!(highlighted("{ * | data 4.0000 | grad 1.0000 }", "#ff000040"));
+    }
+
+    #[test]
+    fn test_dot_graph_escapes_labels() {
+        let a: RefValue = Value::new(1.0);
+        a.get().borrow_mut().set_label("x{1}|\"y\"");
+
+        let graph = Value::generate_dot_graph(&a, false);
+        assert!(graph.contains("x\\{1\\}\\|\\\"y\\\""));
+    }
+
+    #[test]
+    fn test_label() {
+        let a = Value::new(2.0).with_label("a");
+        let b = Value::new(3.0);
+        let x = (a.clone() * b.clone()).with_label("x");
+
+        assert_eq!(a.get().borrow().label(), Some("a"));
+        assert_eq!(b.get().borrow().label(), None);
+        assert!(a.to_string().starts_with("Value(label=a, data=2.0000"));
+        assert!(b.to_string().starts_with("Value(data=3.0000"));
+
+        let graph = Value::generate_mermaid_graph(&x);
+        assert!(graph.contains("s1 : Value(label=x, data=6.0000, op=*"));
+        assert!(graph.contains(" : Value(label=a, "));
+    }
+
+    #[test]
+    fn test_grad_hook_observes() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let h = a.clone() * b.clone();
+        let log = seen.clone();
+        h.register_hook(move |grad| {
+            log.lock().unwrap().push(grad);
+            None
+        });
+
+        let x = h.clone() * 4.0 + h.clone();
+        Value::back_propagate(&x);
+
+        // Called once with the complete gradient of both uses.
+        assert_eq!(*seen.lock().unwrap(), vec![5.0]);
+        assert_eq!(a.get().borrow().grad, 15.0);
+    }
+
+    #[test]
+    fn test_grad_hook_replaces() {
+        let a = Value::new(2.0);
+        let h = Value::pow(a.clone(), 3.0);
+        h.register_hook(|grad: f32| Some(grad.clamp(-1.0, 1.0)));
+        let x = h.clone() * 10.0;
+        Value::back_propagate(&x);
+
+        // The clipped gradient is stored and flows on to the children.
+        assert_eq!(h.get().borrow().grad, 1.0);
+        assert_eq!(a.get().borrow().grad, 12.0);
+    }
+
+    #[test]
+    fn test_grad_hook_order_and_clear() {
+        let a = Value::new(1.0);
+        a.register_hook(|grad: f32| Some(-grad));
+        a.register_hook(|grad: f32| Some(grad * 2.0));
+        let x = a.clone() * 3.0;
+        Value::back_propagate(&x);
+        assert_eq!(a.get().borrow().grad, -6.0);
+
+        a.clear_hooks();
+        Value::back_propagate(&x);
+        assert_eq!(a.get().borrow().grad, 3.0);
+    }
+
+    #[test]
+    fn test_grad_hook_accumulate() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let a = Value::new(1.0);
+        let log = seen.clone();
+        a.register_hook(move |grad| {
+            log.lock().unwrap().push(grad);
+            Some(grad * 0.5)
+        });
+
+        Value::back_propagate_accumulate(&(a.clone() * 2.0));
+        Value::back_propagate_accumulate(&(a.clone() * 4.0));
+
+        // The hook only sees the gradient of each pass, not the accumulated one.
+        assert_eq!(*seen.lock().unwrap(), vec![2.0, 4.0]);
+        assert_eq!(a.get().borrow().grad, 3.0);
+    }
+
+    #[test]
+    fn test_grad_hook_reads_value() {
+        let a = Value::new(4.0);
+        let b = a.clone();
+        a.register_hook(move |grad| Some(grad * b.get().borrow().data));
+        let x = a.clone() * 2.0;
+        Value::back_propagate(&x);
+
+        assert_eq!(a.get().borrow().grad, 8.0);
+    }
+
+    #[test]
+    fn test_back_propagate_seeded() {
+        let x = Value::new(2.0);
+        let y = Value::new(3.0);
+        let a = x.clone() * y.clone();
+        let b = x.clone() + Value::pow(y.clone(), 2.0);
+
+        Value::back_propagate_seeded(&[a, b], &[2.0, -1.0]);
+        // 2 * (y, x) - (1, 2y)
+        assert_eq!(x.get().borrow().grad, 5.0);
+        assert_eq!(y.get().borrow().grad, -2.0);
+    }
+
+    #[test]
+    fn test_back_propagate_seeded_nested_roots() {
+        let x = Value::new(2.0);
+        let h = x.clone() * 3.0;
+        let y = h.clone() * h.clone();
+
+        Value::back_propagate_seeded(&[y.clone(), h.clone()], &[1.0, 1.0]);
+        // h gets 2h from y on top of its own seed.
+        assert_eq!(h.get().borrow().grad, 13.0);
+        assert_eq!(x.get().borrow().grad, 39.0);
+        assert_eq!(y.get().borrow().grad, 1.0);
+    }
+
+    #[test]
+    fn test_back_propagate_seeded_mlp() {
+        let mlp = MultiLayerPerceptron::<f64>::random(3, vec![4, 2]);
+        let x: Vec<RefValue<f64>> = [0.3, -0.7, 1.1].iter().map(|&v| Value::leaf(v)).collect();
+        let outputs = mlp.forward(&x);
+        let seeds = [0.5, -2.0];
+
+        let jac = jacobian(&outputs, &x);
+        Value::back_propagate_seeded(&outputs, &seeds);
+        for (j, input) in x.iter().enumerate() {
+            let expected = seeds[0] * jac[0][j] + seeds[1] * jac[1][j];
+            assert!((input.get().borrow().grad - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_back_propagate_seeded_mismatch() {
+        let x = Value::new(2.0);
+        let y = Value::exp(x.clone());
+
+        assert_eq!(
+            Value::try_back_propagate_seeded(&[y], &[]),
+            Err(Error::ShapeMismatch { expected: 1, actual: 0 })
+        );
+    }
 }